@@ -0,0 +1,212 @@
+// Lower bound on the total displacement of a legalization, so that a
+// result can be compared against something better than zero.
+//
+// The placement is relaxed to a linear program over a grid of bins,
+// one row tall: cell width can be split across bins, and a bin can
+// hold at most its own width of cells.  That is a transportation
+// problem, solved as a min-cost flow of cell width.  A cell moving k
+// rows or bins away has to move at least k - 1 whole rows or bins
+// (nothing is charged for the row or bins it already touches, or for
+// the ones next to them), and that distance, per unit of width, is
+// charged for the width that moves.  To keep the graph small, the
+// cells are grouped into width classes and each class is charged as
+// if all of its cells were as wide as the widest one -- cheaper than
+// the real cost, so the result is still a lower bound.
+//
+// On top of the flow, each cell has to get into the core, and onto a
+// row.  Macros are left out (they can only add displacement, and
+// leaving them out gives the cells more room).  Movement within a bin
+// is not seen, so the bound is loose, but it does grow with
+// congestion the way the legalizers do.
+
+use super::flow::{FlowGraph, EPSILON};
+use super::macros;
+use super::{LegalProblem, Metrics};
+use std::collections::HashMap;
+
+#[derive(Copy, Clone, Debug)]
+pub struct BoundConfig {
+    pub bin_width: Option<f32>, // Bin width; four row heights if not given
+    pub class_ratio: f32,       // Widest to narrowest cell in a width class
+}
+
+impl Default for BoundConfig {
+    fn default() -> Self {
+        BoundConfig {
+            bin_width: None,
+            class_ratio: 1.1,
+        }
+    }
+}
+
+pub struct Bound {
+    pub vertical: f32,   // Getting onto rows, and spreading between them
+    pub horizontal: f32, // Getting into the core, and spreading along rows
+    pub total: f32,
+}
+
+impl Bound {
+    // How far a legalization is above the bound, in percent
+    pub fn gap(&self, metrics: &Metrics) -> Option<f32> {
+        if self.total > 0.0 {
+            Some((metrics.total - self.total) / self.total * 100.0)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn lower_bound(lp: &LegalProblem, config: &BoundConfig) -> Bound {
+    let params = &lp.params;
+    let cells: Vec<_> = lp.blocks.iter().filter(|b| !macros::is_macro(b, params)).collect();
+    if params.grid_y == 0 || params.grid_x == 0 || cells.is_empty() {
+        return Bound {
+            vertical: 0.0,
+            horizontal: 0.0,
+            total: 0.0,
+        };
+    }
+    let rows = params.grid_y;
+    let core_left = params.origin_x;
+    let core_right = core_left + params.grid_x as f32 * params.step_x;
+    let top = params.origin_y + (rows - 1) as f32 * params.step_y;
+    let bin_w = config.bin_width.unwrap_or(4.0 * params.step_y).max(params.step_x);
+    let bins = ((core_right - core_left) / bin_w).ceil().max(1.0) as usize;
+    let bin_of = |x: f32| (((x - core_left) / bin_w).floor().max(0.0) as usize).min(bins - 1);
+
+    // Width classes, and the widest cell in each
+    let narrowest = cells
+        .iter()
+        .map(|c| c.w)
+        .filter(|&w| w > 0.0)
+        .min_by(|a, b| a.partial_cmp(b).expect("Could not compare"))
+        .unwrap_or(1.0);
+    let ratio = config.class_ratio.max(1.0 + EPSILON as f32).ln();
+    let class_of = |w: f32| (w.max(narrowest) / narrowest).ln().div_euclid(ratio) as usize;
+    let classes = cells.iter().map(|c| class_of(c.w)).max().unwrap_or(0) + 1;
+    let mut widest = vec![0.0f32; classes];
+    for cell in &cells {
+        let c = class_of(cell.w);
+        widest[c] = widest[c].max(cell.w);
+    }
+
+    // Nodes: a grid of bins for each class, then the bins themselves
+    // (shared by all of the classes), then the source and the sink.
+    // Supply nodes, for cells that start between the same rows and over
+    // the same bins, are added after that.
+    let grid = |c: usize, r: usize, k: usize| (c * rows + r) * bins + k;
+    let shared = classes * rows * bins;
+    let source = shared + rows * bins;
+    let sink = source + 1;
+
+    // Move each cell into the core and onto the nearest row; that much
+    // displacement is certain.  Then find the rows it is between, and
+    // the bins that it covers.
+    let mut vertical = 0.0;
+    let mut horizontal = 0.0;
+    let mut supplies: HashMap<(usize, usize, usize, usize, usize), f64> = HashMap::new();
+    for cell in &cells {
+        let x = cell.x.clamp(core_left, (core_right - cell.w).max(core_left));
+        horizontal += (x - cell.x).abs();
+        let (low, high) = if cell.y <= params.origin_y {
+            vertical += params.origin_y - cell.y;
+            (0, 0)
+        } else if cell.y >= top {
+            vertical += cell.y - top;
+            (rows - 1, rows - 1)
+        } else {
+            let offset = (cell.y - params.origin_y) / params.step_y;
+            let below = (offset.floor() as usize).min(rows - 2);
+            let up = (offset - below as f32) * params.step_y;
+            vertical += up.min(params.step_y - up);
+            (below, below + 1)
+        };
+        let first = bin_of(x).saturating_sub(1);
+        let last = (bin_of(x + cell.w) + 1).min(bins - 1);
+        *supplies.entry((class_of(cell.w), low, high, first, last)).or_insert(0.0) += cell.w as f64;
+    }
+
+    let mut graph = FlowGraph::new(sink + 1 + supplies.len());
+    for r in 0..rows {
+        for k in 0..bins {
+            let width = (core_left + (k + 1) as f32 * bin_w).min(core_right) - (core_left + k as f32 * bin_w);
+            graph.add_edge(shared + r * bins + k, sink, width as f64, 0.0);
+        }
+    }
+    for (c, &w) in widest.iter().enumerate() {
+        if w <= 0.0 {
+            continue;
+        }
+        let up = (params.step_y / w) as f64;
+        let across = (bin_w / w) as f64;
+        for r in 0..rows {
+            for k in 0..bins {
+                let node = grid(c, r, k);
+                graph.add_edge(node, shared + r * bins + k, f64::MAX, 0.0);
+                if k + 1 < bins {
+                    graph.add_edge(node, grid(c, r, k + 1), f64::MAX, across);
+                    graph.add_edge(grid(c, r, k + 1), node, f64::MAX, across);
+                }
+                if r + 1 < rows {
+                    graph.add_edge(node, grid(c, r + 1, k), f64::MAX, up);
+                    graph.add_edge(grid(c, r + 1, k), node, f64::MAX, up);
+                }
+            }
+        }
+    }
+    // Supply nodes reach the bins they cover, and one more on each
+    // side, on the rows above and below, for free
+    let mut demand = 0.0;
+    let mut keys: Vec<_> = supplies.keys().copied().collect();
+    keys.sort();
+    for (n, key) in keys.iter().enumerate() {
+        let (c, low, high, first, last) = *key;
+        let node = sink + 1 + n;
+        graph.add_edge(source, node, supplies[key], 0.0);
+        demand += supplies[key];
+        for r in [low, high] {
+            for k in first..=last {
+                graph.add_edge(node, grid(c, r, k), f64::MAX, 0.0);
+            }
+            if high == low {
+                break;
+            }
+        }
+    }
+
+    let (flow, _cost) = graph.solve(source, sink);
+    #[cfg(feature = "ldbg")]
+    println!(
+        "Lower bound: {} width classes, {} bins, {:.1} of {:.1} width placed at cost {:.1}",
+        classes, bins, flow, demand, _cost
+    );
+    if demand - flow > 1.0 {
+        println!("**** Rows cannot hold {:.1} of the cell width", demand - flow);
+    }
+
+    // Split the cost of the flow into its vertical and horizontal parts
+    let mut spread_v = 0.0;
+    let mut spread_h = 0.0;
+    for c in 0..classes {
+        for r in 0..rows {
+            for k in 0..bins {
+                for e in &graph.edges[grid(c, r, k)] {
+                    if e.capacity > 0.0 && e.to < shared && e.flow > 0.0 {
+                        if e.to / bins == grid(c, r, k) / bins {
+                            spread_h += e.flow * e.cost;
+                        } else {
+                            spread_v += e.flow * e.cost;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    let vertical = vertical + spread_v as f32;
+    let horizontal = horizontal + spread_h as f32;
+    Bound {
+        vertical,
+        horizontal,
+        total: vertical + horizontal,
+    }
+}
@@ -0,0 +1,320 @@
+// Constraint-graph compaction.  For compaction along one axis, every
+// pair of blocks whose spans on the other axis overlap gets an edge,
+// from the block that comes first (by center) to the one after it,
+// weighted by the size of the first block plus any minimum spacing.
+// The longest path from the boundary to each block is the closest it
+// can get to that boundary without overlapping anything, and the
+// longest path overall is the critical chain -- the blocks that set
+// the extent of the compacted layout.
+//
+// Blocks that overlap on entry are ordered by their centers, so the
+// result never overlaps.
+
+use super::{LegalPosition, LegalProblem};
+use std::fmt;
+use std::str::FromStr;
+
+// Span overlaps smaller than this do not count
+const EPSILON: f32 = 1.0e-3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompactDirection {
+    West,
+    East,
+    South,
+    North,
+}
+
+impl FromStr for CompactDirection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "west" => Ok(CompactDirection::West),
+            "east" => Ok(CompactDirection::East),
+            "south" => Ok(CompactDirection::South),
+            "north" => Ok(CompactDirection::North),
+            _ => Err(format!(
+                "unknown compaction direction {} (west, east, south, north)",
+                s
+            )),
+        }
+    }
+}
+
+pub struct Compaction {
+    pub positions: Vec<LegalPosition>,
+    pub critical: Vec<usize>, // Tags along the critical chain, in order
+    pub length: f32,          // Extent of the critical chain
+}
+
+impl fmt::Display for Compaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "critical chain {:.1} through {} blocks:", self.length, self.critical.len())?;
+        for tag in &self.critical {
+            write!(f, " {}", tag)?;
+        }
+        Ok(())
+    }
+}
+
+// Order the nodes so that every edge (a, b) has a before b.  Anything
+// caught in a cycle goes on the end.
+pub fn topological(n: usize, edges: &Vec<(usize, usize)>) -> Vec<usize> {
+    let mut succs = vec![Vec::new(); n];
+    let mut indegree = vec![0; n];
+    for &(a, b) in edges {
+        succs[a].push(b);
+        indegree[b] += 1;
+    }
+    let mut ready: Vec<usize> = (0..n).filter(|&i| indegree[i] == 0).collect();
+    let mut order = Vec::with_capacity(n);
+    while let Some(i) = ready.pop() {
+        order.push(i);
+        for &s in &succs[i] {
+            indegree[s] -= 1;
+            if indegree[s] == 0 {
+                ready.push(s);
+            }
+        }
+    }
+    if order.len() < n {
+        println!("**** Cycle in constraint graph");
+        let mut placed = vec![false; n];
+        for &i in &order {
+            placed[i] = true;
+        }
+        order.extend((0..n).filter(|&i| !placed[i]));
+    }
+    order
+}
+
+// Constraint graph along one axis.  pos and size are along the axis,
+// low and high are the span on the other axis, and tags break ties
+// between blocks with the same center.  Each edge is (before, after,
+// minimum separation).
+pub fn constraint_graph(
+    pos: &Vec<f32>,
+    size: &Vec<f32>,
+    low: &Vec<f32>,
+    high: &Vec<f32>,
+    tags: &Vec<usize>,
+    spacing: f32,
+) -> Vec<(usize, usize, f32)> {
+    let n = pos.len();
+    let mut order: Vec<usize> = (0..n).collect();
+    let center = |i: usize| pos[i] + size[i] / 2.0;
+    order.sort_by(|&a, &b| {
+        center(a)
+            .partial_cmp(&center(b))
+            .expect("Could not compare")
+            .then(tags[a].cmp(&tags[b]))
+    });
+
+    let mut edges = Vec::new();
+    for (k, &a) in order.iter().enumerate() {
+        for &b in &order[k + 1..] {
+            if high[a].min(high[b]) - low[a].max(low[b]) > EPSILON {
+                edges.push((a, b, size[a] + spacing));
+            }
+        }
+    }
+    edges
+}
+
+// Longest path from the boundary at base to every node.  Returns the
+// distances and, for each node, the predecessor that set it.
+pub fn longest_path(
+    n: usize,
+    edges: &Vec<(usize, usize, f32)>,
+    base: f32,
+) -> (Vec<f32>, Vec<Option<usize>>) {
+    let pairs: Vec<(usize, usize)> = edges.iter().map(|&(a, b, _)| (a, b)).collect();
+    let mut preds = vec![Vec::new(); n];
+    for &(a, b, w) in edges {
+        preds[b].push((a, w));
+    }
+    let mut dist = vec![base; n];
+    let mut from = vec![None; n];
+    for i in topological(n, &pairs) {
+        for &(a, w) in &preds[i] {
+            if dist[a] + w > dist[i] {
+                dist[i] = dist[a] + w;
+                from[i] = Some(a);
+            }
+        }
+    }
+    (dist, from)
+}
+
+// The chain of nodes ending at the one furthest from the boundary, and
+// its extent
+fn critical_chain(dist: &Vec<f32>, size: &Vec<f32>, from: &Vec<Option<usize>>, base: f32) -> (Vec<usize>, f32) {
+    let mut last = None;
+    let mut length = 0.0;
+    for i in 0..dist.len() {
+        if dist[i] + size[i] - base > length {
+            length = dist[i] + size[i] - base;
+            last = Some(i);
+        }
+    }
+    let mut chain = Vec::new();
+    while let Some(i) = last {
+        chain.push(i);
+        last = from[i];
+    }
+    chain.reverse();
+    (chain, length)
+}
+
+// Compact one axis toward low (packing toward base) or high (packing
+// toward base from above).  Returns the new positions, the chain, and
+// its length.
+#[allow(clippy::too_many_arguments)]
+fn compact_axis(
+    pos: &Vec<f32>,
+    size: &Vec<f32>,
+    low: &Vec<f32>,
+    high: &Vec<f32>,
+    tags: &Vec<usize>,
+    spacing: f32,
+    base: f32,
+    toward_low: bool,
+) -> (Vec<f32>, Vec<usize>, f32) {
+    let n = pos.len();
+    if toward_low {
+        let edges = constraint_graph(pos, size, low, high, tags, spacing);
+        let (dist, from) = longest_path(n, &edges, base);
+        let (chain, length) = critical_chain(&dist, size, &from, base);
+        return (dist, chain, length);
+    }
+    // Mirror about the base, so that packing up becomes packing down
+    let mirrored: Vec<f32> = (0..n).map(|i| base - (pos[i] + size[i])).collect();
+    let edges = constraint_graph(&mirrored, size, low, high, tags, spacing);
+    let (dist, from) = longest_path(n, &edges, 0.0);
+    let (mut chain, length) = critical_chain(&dist, size, &from, 0.0);
+    chain.reverse();
+    let result = (0..n).map(|i| base - dist[i] - size[i]).collect();
+    (result, chain, length)
+}
+
+fn positions(lp: &LegalProblem, x: &Vec<f32>, y: &Vec<f32>) -> Vec<LegalPosition> {
+    lp.blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| LegalPosition {
+            block_tag: block.tag,
+            x: x[i],
+            y: y[i],
+            h: block.h,
+            w: block.w,
+            original_x: block.x,
+            original_y: block.y,
+        })
+        .collect()
+}
+
+// Compact every block as far as it will go in one direction.  West and
+// south pack against the core origin; east and north keep the extent
+// of the compacted layout, and pack against the far side of it, so the
+// critical chain starts at the origin either way.
+pub fn compact(lp: &LegalProblem, direction: CompactDirection, spacing: f32) -> Compaction {
+    let blocks = &lp.blocks;
+    let x: Vec<f32> = blocks.iter().map(|b| b.x).collect();
+    let y: Vec<f32> = blocks.iter().map(|b| b.y).collect();
+    let w: Vec<f32> = blocks.iter().map(|b| b.w).collect();
+    let h: Vec<f32> = blocks.iter().map(|b| b.h).collect();
+    let top: Vec<f32> = blocks.iter().map(|b| b.y + b.h).collect();
+    let right: Vec<f32> = blocks.iter().map(|b| b.x + b.w).collect();
+    let tags: Vec<usize> = blocks.iter().map(|b| b.tag).collect();
+
+    let (x, y, chain, length) = match direction {
+        CompactDirection::West => {
+            let (x, chain, length) = compact_axis(&x, &w, &y, &top, &tags, spacing, lp.params.origin_x, true);
+            (x, y, chain, length)
+        }
+        CompactDirection::South => {
+            let (y, chain, length) = compact_axis(&y, &h, &x, &right, &tags, spacing, lp.params.origin_y, true);
+            (x, y, chain, length)
+        }
+        CompactDirection::East => {
+            let (x, chain, length) = compact_axis(&x, &w, &y, &top, &tags, spacing, 0.0, false);
+            let shift = lp.params.origin_x + length;
+            (x.iter().map(|x| x + shift).collect(), y, chain, length)
+        }
+        CompactDirection::North => {
+            let (y, chain, length) = compact_axis(&y, &h, &x, &right, &tags, spacing, 0.0, false);
+            let shift = lp.params.origin_y + length;
+            (x, y.iter().map(|y| y + shift).collect(), chain, length)
+        }
+    };
+
+    Compaction {
+        positions: positions(lp, &x, &y),
+        critical: chain.iter().map(|&i| blocks[i].tag).collect(),
+        length,
+    }
+}
+
+// Compact toward a point: blocks whose centers are to the left of it
+// pack rightward against it, and blocks to the right pack leftward;
+// then the same vertically.  The critical chain reported is the
+// longest of the four half-chains.
+pub fn compact_toward(lp: &LegalProblem, target_x: f32, target_y: f32, spacing: f32) -> Compaction {
+    let blocks = &lp.blocks;
+    let n = blocks.len();
+    let mut x: Vec<f32> = blocks.iter().map(|b| b.x).collect();
+    let mut y: Vec<f32> = blocks.iter().map(|b| b.y).collect();
+    let w: Vec<f32> = blocks.iter().map(|b| b.w).collect();
+    let h: Vec<f32> = blocks.iter().map(|b| b.h).collect();
+    let tags: Vec<usize> = blocks.iter().map(|b| b.tag).collect();
+
+    let mut critical = Vec::new();
+    let mut longest = 0.0;
+
+    for horizontal in [true, false] {
+        let (pos, size, low, span, target) = if horizontal {
+            (&x, &w, &y, &h, target_x)
+        } else {
+            (&y, &h, &x, &w, target_y)
+        };
+        let mut result = pos.clone();
+        for before in [true, false] {
+            // The blocks on one side of the target
+            let side: Vec<usize> = (0..n)
+                .filter(|&i| (pos[i] + size[i] / 2.0 < target) == before)
+                .collect();
+            let sub = |v: &Vec<f32>| side.iter().map(|&i| v[i]).collect::<Vec<f32>>();
+            let high: Vec<f32> = side.iter().map(|&i| low[i] + span[i]).collect();
+            let side_tags: Vec<usize> = side.iter().map(|&i| tags[i]).collect();
+            let (packed, chain, length) = compact_axis(
+                &sub(pos),
+                &sub(size),
+                &sub(low),
+                &high,
+                &side_tags,
+                spacing,
+                target,
+                !before,
+            );
+            for (k, &i) in side.iter().enumerate() {
+                result[i] = packed[k];
+            }
+            if length > longest {
+                longest = length;
+                critical = chain.iter().map(|&k| blocks[side[k]].tag).collect();
+            }
+        }
+        if horizontal {
+            x = result;
+        } else {
+            y = result;
+        }
+    }
+
+    Compaction {
+        positions: positions(lp, &x, &y),
+        critical,
+        length: longest,
+    }
+}
@@ -0,0 +1,622 @@
+// Detailed placement.  Once the blocks are legal, they can be moved
+// around a little to reduce displacement or wirelength, without ever
+// becoming illegal again.  Only standard cells that sit in a row are
+// moved; macros and anything else are fixed obstacles.
+//
+// The moves are:
+//   - adjacent swap: two neighbouring cells in a row trade places,
+//     within the span they cover together
+//   - reordering: every permutation of a small window of cells in a
+//     row is tried, each placed as close as possible to where its
+//     cells want to be within the free span around the window
+//   - global swap: a cell trades places with a cell near where it
+//     wants to be, if each fits into the space around the other
+//   - vertical swap: the same, with the cells directly above and below
+// A move is kept only if it lowers the cost of the cells it touches
+// (their displacement, or the wirelength of their nets).
+
+use super::getline;
+use super::placerow;
+use super::{block_at, LegalBlock, LegalParams, LegalPosition, LegalProblem};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::str::FromStr;
+
+// Position differences smaller than this are rounding noise
+const EPSILON: f32 = 1.0e-3;
+
+// Largest reordering window (4! orders)
+const MAX_WINDOW: usize = 4;
+
+// A net connects the centers of the blocks with these tags
+#[derive(Clone, Debug)]
+pub struct Net {
+    pub pins: Vec<usize>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DetailedObjective {
+    Displacement, // Distance from the original positions
+    Hpwl,         // Half-perimeter wirelength of the nets
+}
+
+impl FromStr for DetailedObjective {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "displacement" => Ok(DetailedObjective::Displacement),
+            "hpwl" => Ok(DetailedObjective::Hpwl),
+            _ => Err(format!("unknown detailed objective {} (displacement, hpwl)", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct DetailedConfig {
+    pub objective: DetailedObjective,
+    pub passes: usize,     // Rounds of all of the moves
+    pub window: usize,     // Cells reordered together (at most 4)
+    pub swap_range: usize, // Cells either side of the target tried in global swaps
+}
+
+impl Default for DetailedConfig {
+    fn default() -> Self {
+        DetailedConfig {
+            objective: DetailedObjective::Displacement,
+            passes: 3,
+            window: 3,
+            swap_range: 3,
+        }
+    }
+}
+
+// Read nets: the number of nets, then one line for each net with the
+// tags of the blocks on it.  Lines starting with # are comments.
+pub fn load_nets(filename: &String) -> Vec<Net> {
+    let f = File::open(filename).unwrap();
+    let mut reader = BufReader::with_capacity(32000, f);
+
+    let line = getline(&mut reader).unwrap();
+    let num_nets = line.parse::<usize>().unwrap();
+    let mut nets = Vec::with_capacity(num_nets);
+    for _i in 0..num_nets {
+        let line = getline(&mut reader).unwrap();
+        let pins = line.split_whitespace().map(|s| s.parse::<usize>().unwrap()).collect();
+        nets.push(Net { pins });
+    }
+    nets
+}
+
+// Every order of 0..n
+fn permutations(n: usize) -> Vec<Vec<usize>> {
+    if n == 0 {
+        return vec![Vec::new()];
+    }
+    let mut result = Vec::new();
+    for p in permutations(n - 1) {
+        for at in 0..=p.len() {
+            let mut q = p.clone();
+            q.insert(at, n - 1);
+            result.push(q);
+        }
+    }
+    result
+}
+
+struct Detailed<'a> {
+    params: &'a LegalParams,
+    config: &'a DetailedConfig,
+    originals: HashMap<usize, &'a LegalBlock>, // For the weight and priority of each block
+    pos: Vec<LegalPosition>,
+    rows: Vec<Vec<usize>>,              // Movable cells in each row, by x
+    place: Vec<Option<(usize, usize)>>, // Row, and index in the row, of each movable cell
+    fixed: Vec<Vec<(f32, f32)>>,        // Everything else, in each row
+    nets: Vec<Vec<usize>>,              // Pins, as indices into pos
+    cell_nets: Vec<Vec<usize>>,
+}
+
+impl Detailed<'_> {
+    fn core_right(&self) -> f32 {
+        self.params.origin_x + self.params.grid_x as f32 * self.params.step_x
+    }
+
+    fn row_y(&self, row: usize) -> f32 {
+        self.params.origin_y + row as f32 * self.params.step_y
+    }
+
+    fn hpwl(&self, net: usize) -> f32 {
+        let pins = &self.nets[net];
+        if pins.len() < 2 {
+            return 0.0;
+        }
+        let (mut x0, mut x1, mut y0, mut y1) = (f32::MAX, f32::MIN, f32::MAX, f32::MIN);
+        for &i in pins {
+            let p = &self.pos[i];
+            let (cx, cy) = (p.x + p.w / 2.0, p.y + p.h / 2.0);
+            x0 = x0.min(cx);
+            x1 = x1.max(cx);
+            y0 = y0.min(cy);
+            y1 = y1.max(cy);
+        }
+        (x1 - x0) + (y1 - y0)
+    }
+
+    // Cost of the cells given, or of the nets they are on
+    fn cost(&self, cells: &[usize]) -> f32 {
+        match self.config.objective {
+            DetailedObjective::Displacement => cells
+                .iter()
+                .map(|&i| {
+                    let p = &self.pos[i];
+                    let weight = self.originals.get(&p.block_tag).map_or(1.0, |b| b.weight);
+                    ((p.x - p.original_x).abs() + (p.y - p.original_y).abs()) * weight
+                })
+                .sum(),
+            DetailedObjective::Hpwl => {
+                let mut nets: Vec<usize> = cells
+                    .iter()
+                    .flat_map(|&i| self.cell_nets[i].iter().copied())
+                    .collect();
+                nets.sort_unstable();
+                nets.dedup();
+                nets.iter().map(|&n| self.hpwl(n)).sum()
+            }
+        }
+    }
+
+    // Where a cell would like to be: its original position, or the
+    // middle of the boxes of the other pins on its nets
+    fn target(&self, i: usize) -> (f32, f32) {
+        let p = &self.pos[i];
+        if self.config.objective == DetailedObjective::Displacement || self.cell_nets[i].is_empty() {
+            return (p.original_x, p.original_y);
+        }
+        let mut xs = Vec::new();
+        let mut ys = Vec::new();
+        for &n in &self.cell_nets[i] {
+            let (mut x0, mut x1, mut y0, mut y1) = (f32::MAX, f32::MIN, f32::MAX, f32::MIN);
+            for &j in self.nets[n].iter().filter(|&&j| j != i) {
+                let q = &self.pos[j];
+                x0 = x0.min(q.x + q.w / 2.0);
+                x1 = x1.max(q.x + q.w / 2.0);
+                y0 = y0.min(q.y + q.h / 2.0);
+                y1 = y1.max(q.y + q.h / 2.0);
+            }
+            if x0 <= x1 {
+                xs.extend([x0, x1]);
+                ys.extend([y0, y1]);
+            }
+        }
+        if xs.is_empty() {
+            return (p.x, p.y);
+        }
+        xs.sort_by(|a, b| a.partial_cmp(b).expect("Could not compare"));
+        ys.sort_by(|a, b| a.partial_cmp(b).expect("Could not compare"));
+        let mid = xs.len() / 2;
+        (xs[mid] - p.w / 2.0, ys[mid] - p.h / 2.0)
+    }
+
+    // Free span around the cell at index k of a row: from the end of
+    // whatever is to its left to the start of whatever is to its right
+    fn slot(&self, row: usize, k: usize) -> (f32, f32) {
+        let cells = &self.rows[row];
+        let p = &self.pos[cells[k]];
+        let mut low = match k {
+            0 => self.params.origin_x,
+            _ => {
+                let q = &self.pos[cells[k - 1]];
+                q.x + q.w
+            }
+        };
+        let mut high = match cells.get(k + 1) {
+            Some(&j) => self.pos[j].x,
+            None => self.core_right(),
+        };
+        for &(a, b) in &self.fixed[row] {
+            if b <= p.x + EPSILON {
+                low = low.max(b);
+            } else if a >= p.x + p.w - EPSILON {
+                high = high.min(a);
+            }
+        }
+        (low, high)
+    }
+
+    // True if nothing fixed lies between the n cells from index k
+    fn contiguous(&self, row: usize, k: usize, n: usize) -> bool {
+        (k..k + n - 1).all(|j| {
+            let next = &self.pos[self.rows[row][j + 1]];
+            self.slot(row, j).1 >= next.x - EPSILON
+        })
+    }
+
+    // Best x for a cell within a span, starting from x
+    fn fit(&self, i: usize, low: f32, high: f32, x: f32) -> f32 {
+        let x = match self.config.objective {
+            DetailedObjective::Displacement => self.pos[i].original_x,
+            DetailedObjective::Hpwl => x,
+        };
+        x.min(high - self.pos[i].w).max(low)
+    }
+
+    // Two neighbours in a row trade places
+    fn adjacent_swap(&mut self, row: usize, k: usize) -> bool {
+        if !self.contiguous(row, k, 2) {
+            return false;
+        }
+        let (a, b) = (self.rows[row][k], self.rows[row][k + 1]);
+        let before = self.cost(&[a, b]);
+        let (ax, bx) = (self.pos[a].x, self.pos[b].x);
+        self.pos[b].x = ax;
+        self.pos[a].x = bx + self.pos[b].w - self.pos[a].w;
+        if self.cost(&[a, b]) < before - EPSILON {
+            self.rows[row].swap(k, k + 1);
+            self.place[a] = Some((row, k + 1));
+            self.place[b] = Some((row, k));
+            return true;
+        }
+        self.pos[a].x = ax;
+        self.pos[b].x = bx;
+        false
+    }
+
+    // Try every order of the cells starting at index k of a row
+    fn reorder(&mut self, row: usize, k: usize, orders: &Vec<Vec<usize>>) -> bool {
+        let n = orders[0].len();
+        if !self.contiguous(row, k, n) {
+            return false;
+        }
+        let cells: Vec<usize> = self.rows[row][k..k + n].to_vec();
+        let (low, _) = self.slot(row, k);
+        let (_, high) = self.slot(row, k + n - 1);
+        let before = self.cost(&cells);
+        let saved: Vec<f32> = cells.iter().map(|&i| self.pos[i].x).collect();
+
+        let mut best = before - EPSILON;
+        let mut best_order = None;
+        for order in orders {
+            let blocks: Vec<LegalBlock> = order
+                .iter()
+                .map(|&o| {
+                    let p = &self.pos[cells[o]];
+                    let x = match self.config.objective {
+                        DetailedObjective::Displacement => p.original_x,
+                        DetailedObjective::Hpwl => saved[o],
+                    };
+                    LegalBlock {
+                        x,
+                        ..block_at(p, &self.originals)
+                    }
+                })
+                .collect();
+            let xs = placerow::place_row(&blocks, low, high);
+            for (&o, &x) in order.iter().zip(&xs) {
+                self.pos[cells[o]].x = x;
+            }
+            let cost = self.cost(&cells);
+            if cost < best {
+                best = cost;
+                best_order = Some((order, xs));
+            }
+        }
+
+        match best_order {
+            Some((order, xs)) => {
+                for (slot, (&o, x)) in order.iter().zip(xs).enumerate() {
+                    let i = cells[o];
+                    self.pos[i].x = x;
+                    self.rows[row][k + slot] = i;
+                    self.place[i] = Some((row, k + slot));
+                }
+                true
+            }
+            None => {
+                for (&i, &x) in cells.iter().zip(&saved) {
+                    self.pos[i].x = x;
+                }
+                false
+            }
+        }
+    }
+
+    // Two cells trade places, if each fits in the space around the other
+    fn swap(&mut self, a: usize, b: usize) -> bool {
+        let ((ra, ka), (rb, kb)) = match (self.place[a], self.place[b]) {
+            (Some(pa), Some(pb)) => (pa, pb),
+            _ => return false,
+        };
+        if a == b {
+            return false;
+        }
+        if ra == rb && ka.abs_diff(kb) == 1 {
+            return self.adjacent_swap(ra, ka.min(kb));
+        }
+        let (alow, ahigh) = self.slot(ra, ka);
+        let (blow, bhigh) = self.slot(rb, kb);
+        if self.pos[a].w > bhigh - blow + EPSILON || self.pos[b].w > ahigh - alow + EPSILON {
+            return false;
+        }
+
+        let before = self.cost(&[a, b]);
+        let (pa, pb) = (self.pos[a], self.pos[b]);
+        let xa = self.fit(a, blow, bhigh, pb.x);
+        let xb = self.fit(b, alow, ahigh, pa.x);
+        self.pos[a].x = xa;
+        self.pos[a].y = pb.y;
+        self.pos[b].x = xb;
+        self.pos[b].y = pa.y;
+        if self.cost(&[a, b]) < before - EPSILON {
+            self.rows[ra][ka] = b;
+            self.rows[rb][kb] = a;
+            self.place[a] = Some((rb, kb));
+            self.place[b] = Some((ra, ka));
+            return true;
+        }
+        self.pos[a] = pa;
+        self.pos[b] = pb;
+        false
+    }
+
+    // Try swapping with the cells of a row nearest to x
+    fn swap_near(&mut self, i: usize, row: usize, x: f32, range: usize) -> bool {
+        let at = self.rows[row].partition_point(|&j| self.pos[j].x < x);
+        let low = at.saturating_sub(range);
+        let high = (at + range).min(self.rows[row].len());
+        for k in low..high {
+            let j = self.rows[row][k];
+            if self.swap(i, j) {
+                return true;
+            }
+        }
+        false
+    }
+
+    // Swap toward where the cell wants to be
+    fn global_swap(&mut self, i: usize) -> bool {
+        let (tx, ty) = self.target(i);
+        let row = ((ty - self.params.origin_y) / self.params.step_y).round();
+        let row = (row.max(0.0) as usize).min(self.params.grid_y - 1);
+        let low = row.saturating_sub(1);
+        let high = (row + 1).min(self.params.grid_y - 1);
+        for r in low..=high {
+            if self.swap_near(i, r, tx, self.config.swap_range) {
+                return true;
+            }
+        }
+        false
+    }
+
+    // Swap with the cells directly above or below
+    fn vertical_swap(&mut self, i: usize) -> bool {
+        let (row, _) = match self.place[i] {
+            Some(p) => p,
+            None => return false,
+        };
+        let x = self.pos[i].x;
+        for r in [row.wrapping_sub(1), row + 1] {
+            if r < self.params.grid_y && self.swap_near(i, r, x, 1) {
+                return true;
+            }
+        }
+        false
+    }
+
+    // One round of every move; returns the number of moves made
+    fn pass(&mut self, orders: &Vec<Vec<usize>>) -> usize {
+        let mut moves = 0;
+        for row in 0..self.rows.len() {
+            for k in 0..self.rows[row].len().saturating_sub(1) {
+                if self.adjacent_swap(row, k) {
+                    moves += 1;
+                }
+            }
+            let n = orders[0].len();
+            if n > 1 {
+                for k in 0..(self.rows[row].len() + 1).saturating_sub(n) {
+                    if self.reorder(row, k, orders) {
+                        moves += 1;
+                    }
+                }
+            }
+        }
+        for i in 0..self.pos.len() {
+            if self.global_swap(i) {
+                moves += 1;
+            }
+            if self.vertical_swap(i) {
+                moves += 1;
+            }
+        }
+        moves
+    }
+}
+
+// Improve a legal placement of the problem.  The positions come back
+// in the same order, still legal, with the cost no higher than
+// before; displacement is weighted by the weights of the blocks.
+// Fixed positions are obstacles, and are not returned.  Nets are only
+// needed for the HPWL objective; pins that are not in the placement
+// are ignored.
+pub fn improve(
+    lp: &LegalProblem,
+    placement: &Vec<LegalPosition>,
+    fixed: &Vec<LegalPosition>,
+    nets: &Vec<Net>,
+    config: &DetailedConfig,
+) -> Vec<LegalPosition> {
+    let params = &lp.params;
+    let n = placement.len();
+    let mut detailed = Detailed {
+        params,
+        config,
+        originals: lp.blocks.iter().map(|b| (b.tag, b)).collect(),
+        pos: placement.clone(),
+        rows: vec![Vec::new(); params.grid_y],
+        place: vec![None; n],
+        fixed: vec![Vec::new(); params.grid_y],
+        nets: Vec::new(),
+        cell_nets: vec![Vec::new(); n],
+    };
+    if params.grid_y == 0 {
+        return detailed.pos;
+    }
+
+    let core_right = detailed.core_right();
+    let mut obstacles = Vec::new();
+    for (i, p) in placement.iter().enumerate() {
+        let row = ((p.y - params.origin_y) / params.step_y).round();
+        let in_row = row >= 0.0
+            && (row as usize) < params.grid_y
+            && (p.y - detailed.row_y(row as usize)).abs() < EPSILON
+            && p.h <= params.step_y + EPSILON
+            && p.x >= params.origin_x - EPSILON
+            && p.x + p.w <= core_right + EPSILON;
+        if in_row {
+            detailed.rows[row as usize].push(i);
+        } else {
+            obstacles.push(p);
+        }
+    }
+    for p in obstacles.into_iter().chain(fixed) {
+        let first = ((p.y - params.origin_y) / params.step_y + EPSILON).floor().max(0.0) as usize;
+        let last = ((p.y + p.h - params.origin_y) / params.step_y - EPSILON).ceil().max(0.0) as usize;
+        for r in first.min(params.grid_y)..last.min(params.grid_y) {
+            detailed.fixed[r].push((p.x, p.x + p.w));
+        }
+    }
+    for row in 0..params.grid_y {
+        let pos = &detailed.pos;
+        detailed.rows[row].sort_by(|&a, &b| pos[a].x.partial_cmp(&pos[b].x).expect("Could not compare"));
+        for (k, &i) in detailed.rows[row].iter().enumerate() {
+            detailed.place[i] = Some((row, k));
+        }
+    }
+
+    if config.objective == DetailedObjective::Hpwl {
+        let index: HashMap<usize, usize> =
+            placement.iter().enumerate().map(|(i, p)| (p.block_tag, i)).collect();
+        for net in nets {
+            let pins: Vec<usize> = net.pins.iter().filter_map(|t| index.get(t).copied()).collect();
+            let id = detailed.nets.len();
+            for &i in &pins {
+                detailed.cell_nets[i].push(id);
+            }
+            detailed.nets.push(pins);
+        }
+    }
+
+    let orders = permutations(config.window.clamp(1, MAX_WINDOW));
+    for _pass in 0..config.passes {
+        let moves = detailed.pass(&orders);
+        #[cfg(feature = "ldbg")]
+        println!("Detailed pass {}: {} moves", _pass, moves);
+        if moves == 0 {
+            break;
+        }
+    }
+    detailed.pos
+}
+
+// Total half-perimeter wirelength of the nets, with pins at block centers
+pub fn hpwl(placement: &Vec<LegalPosition>, nets: &Vec<Net>) -> f32 {
+    let index: HashMap<usize, &LegalPosition> =
+        placement.iter().map(|p| (p.block_tag, p)).collect();
+    let mut total = 0.0;
+    for net in nets {
+        let pins: Vec<&LegalPosition> = net.pins.iter().filter_map(|t| index.get(t).copied()).collect();
+        if pins.len() < 2 {
+            continue;
+        }
+        let xs = pins.iter().map(|p| p.x + p.w / 2.0);
+        let ys = pins.iter().map(|p| p.y + p.h / 2.0);
+        total += xs.clone().fold(f32::MIN, f32::max) - xs.fold(f32::MAX, f32::min);
+        total += ys.clone().fold(f32::MIN, f32::max) - ys.fold(f32::MAX, f32::min);
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::legalize::random::Rng;
+    use crate::legalize::tetris::{self, TetrisConfig};
+    use crate::legalize::{count_violations, Metrics};
+
+    // Random cells, a few of them two rows tall, legalized with gap-aware
+    // Tetris around two fixed blocks, and random nets over them
+    fn legal_placement(seed: u64) -> (LegalProblem, Vec<LegalPosition>, Vec<LegalPosition>, Vec<Net>) {
+        let mut rng = Rng::new(seed);
+        let params = LegalParams {
+            grid_x: 60,
+            grid_y: 8,
+            origin_x: 0.0,
+            origin_y: 0.0,
+            step_x: 1.0,
+            step_y: 10.0,
+            alpha_right: 2.0,
+            alpha_left: 0.5,
+        };
+        let blocks: Vec<LegalBlock> = (0..50)
+            .map(|tag| LegalBlock {
+                tag,
+                x: rng.range(0.0, 55.0),
+                y: rng.range(0.0, 70.0),
+                h: if tag % 10 == 0 { 20.0 } else { 10.0 },
+                w: (2 + rng.below(5)) as f32,
+                weight: 1.0,
+                priority: 0,
+            })
+            .collect();
+        let lp = LegalProblem { blocks, params };
+        let fixed: Vec<LegalPosition> = [(20.0, 20.0, 8.0, 20.0), (40.0, 50.0, 6.0, 10.0)]
+            .iter()
+            .enumerate()
+            .map(|(i, &(x, y, w, h))| LegalPosition {
+                block_tag: 100 + i,
+                x,
+                y,
+                h,
+                w,
+                original_x: x,
+                original_y: y,
+            })
+            .collect();
+        let placement = tetris::legalize_gaps(&lp, &TetrisConfig::default(), &fixed);
+        let nets = (0..30)
+            .map(|_| Net {
+                pins: (0..2 + rng.below(4)).map(|_| rng.below(50)).collect(),
+            })
+            .collect();
+        (lp, placement, fixed, nets)
+    }
+
+    fn with_fixed(placement: &Vec<LegalPosition>, fixed: &Vec<LegalPosition>) -> Vec<LegalPosition> {
+        placement.iter().chain(fixed).copied().collect()
+    }
+
+    #[test]
+    fn improve_keeps_legal_and_never_raises_cost() {
+        for seed in 1..=10 {
+            let (lp, placement, fixed, nets) = legal_placement(seed);
+            let params = lp.params;
+            assert_eq!(count_violations(&with_fixed(&placement, &fixed), &params), 0);
+            for objective in [DetailedObjective::Displacement, DetailedObjective::Hpwl] {
+                let config = DetailedConfig {
+                    objective,
+                    ..Default::default()
+                };
+                let improved = improve(&lp, &placement, &fixed, &nets, &config);
+                assert_eq!(improved.len(), placement.len());
+                let all = with_fixed(&improved, &fixed);
+                assert_eq!(count_violations(&all, &params), 0, "seed {} {:?}", seed, objective);
+                let (before, after) = match objective {
+                    DetailedObjective::Displacement => (Metrics::new(&placement).total, Metrics::new(&improved).total),
+                    DetailedObjective::Hpwl => (hpwl(&placement, &nets), hpwl(&improved, &nets)),
+                };
+                assert!(after <= before + EPSILON, "seed {} {:?}: {} -> {}", seed, objective, before, after);
+            }
+        }
+    }
+}
@@ -0,0 +1,318 @@
+// Exact single-row legalization by dynamic programming.  With the
+// cells of a row in a fixed order, and positions restricted to sites,
+// the best placement is found by filling in, for each cell and each
+// site, the cheapest way to place that cell and everything before it
+// with the cell starting at that site.  With a running minimum over
+// the sites, this is O(cells * sites) for a row, and works for any
+// cost function (not just convex ones).  Sites covered by fixed blocks
+// are skipped, so cells can be placed on either side of them.
+//
+// `place_row` is the building block.  `optimize` keeps the row and
+// order of every cell in an existing legalization, and re-places each
+// row exactly -- which shows how far a greedy legalizer is from the
+// best it could have done with the same assignment.  `legalize` is a
+// complete legalizer, taking the rows and order from gap-aware Tetris,
+// and row fill can use `place_row` for its rows (`legalize_exact`).
+
+use super::macros;
+use super::tetris::{self, TetrisConfig};
+use super::{block_at, LegalBlock, LegalParams, LegalPosition, LegalProblem};
+use std::collections::HashMap;
+
+// Widths this close to a whole number of sites are rounded down
+const EPSILON: f32 = 1.0e-3;
+
+// Largest table place_row will build, in entries (cells times sites).
+// Each entry is a usize, so this is 128MB on a 64-bit machine; a row
+// with thousands of cells and tens of thousands of sites would need
+// several times that.
+pub const MAX_TABLE: usize = 1 << 24;
+
+// True if place_row can place the blocks on a row of the sites given
+// within MAX_TABLE
+pub fn fits_table(blocks: &Vec<LegalBlock>, params: &LegalParams) -> bool {
+    blocks.len().saturating_mul(params.grid_x + 1) <= MAX_TABLE
+}
+
+// Cost of placing a block with its left edge at x
+pub fn displacement(block: &LegalBlock, x: f32) -> f32 {
+    (x - block.x).abs() * block.weight
+}
+
+fn sites_for(params: &LegalParams, w: f32) -> usize {
+    (w / params.step_x - EPSILON).ceil().max(0.0) as usize
+}
+
+// Best x positions for the blocks, in the order given, on the sites of
+// a row.  blocked marks the sites that cannot be used.  Returns None
+// if the blocks cannot all fit.  The table of choices is cells times
+// sites; callers with large rows check fits_table first.
+pub fn place_row<F>(blocks: &Vec<LegalBlock>, params: &LegalParams, blocked: &Vec<bool>, cost: &F) -> Option<Vec<f32>>
+where
+    F: Fn(&LegalBlock, f32) -> f32,
+{
+    let sites = params.grid_x;
+    if blocks.is_empty() {
+        return Some(Vec::new());
+    }
+    // First blocked site at or after each site, to check fits quickly
+    let mut next_blocked = vec![sites; sites + 1];
+    for s in (0..sites).rev() {
+        next_blocked[s] = if blocked[s] { s } else { next_blocked[s + 1] };
+    }
+    let x_of = |s: usize| params.origin_x + s as f32 * params.step_x;
+
+    // best[s]: cheapest placement of the blocks so far, with the last
+    // one ending at or before site s.  choice[i][s]: where block i
+    // starts in that placement.
+    let n = blocks.len();
+    let mut best = vec![0.0f32; sites + 1];
+    let mut choice = vec![vec![usize::MAX; sites + 1]; n];
+    for (i, block) in blocks.iter().enumerate() {
+        let width = sites_for(params, block.w);
+        let mut next = vec![f32::MAX; sites + 1];
+        for end in width..=sites {
+            // Carry forward the best placement ending earlier
+            next[end] = next[end.saturating_sub(1)];
+            choice[i][end] = if end > 0 { choice[i][end - 1] } else { usize::MAX };
+            let start = end - width;
+            if next_blocked[start] < end || best[start] == f32::MAX {
+                continue;
+            }
+            let c = best[start] + cost(block, x_of(start));
+            if c < next[end] {
+                next[end] = c;
+                choice[i][end] = start;
+            }
+        }
+        best = next;
+        if best[sites] == f32::MAX {
+            return None;
+        }
+    }
+
+    // Walk back from the end of the row
+    let mut xs = vec![0.0; n];
+    let mut end = sites;
+    for i in (0..n).rev() {
+        let start = choice[i][end];
+        xs[i] = x_of(start);
+        end = start;
+    }
+    Some(xs)
+}
+
+// Sites in each row covered by the fixed positions
+fn blocked_sites(params: &LegalParams, fixed: &Vec<LegalPosition>) -> Vec<Vec<bool>> {
+    let mut blocked = vec![vec![false; params.grid_x]; params.grid_y];
+    for f in fixed {
+        let first = ((f.y - params.origin_y) / params.step_y + EPSILON).floor().max(0.0) as usize;
+        let last = ((f.y + f.h - params.origin_y) / params.step_y - EPSILON).ceil().max(0.0) as usize;
+        let left = ((f.x - params.origin_x) / params.step_x + EPSILON).floor().max(0.0) as usize;
+        let right = ((f.x + f.w - params.origin_x) / params.step_x - EPSILON).ceil().max(0.0) as usize;
+        for row in blocked.iter_mut().take(last.min(params.grid_y)).skip(first) {
+            for site in row.iter_mut().take(right.min(params.grid_x)).skip(left) {
+                *site = true;
+            }
+        }
+    }
+    blocked
+}
+
+// Add a row of blocks, at the x positions given, to the positions
+pub fn emit_row(row: usize, blocks: &Vec<LegalBlock>, xs: Vec<f32>, params: &LegalParams, positions: &mut Vec<LegalPosition>) {
+    let y = params.origin_y + row as f32 * params.step_y;
+    for (block, x) in blocks.iter().zip(xs) {
+        positions.push(LegalPosition {
+            block_tag: block.tag,
+            x,
+            y,
+            h: block.h,
+            w: block.w,
+            original_x: block.x,
+            original_y: block.y,
+        });
+    }
+}
+
+// Re-place every row of a legalization of the problem exactly, keeping
+// each cell in its row and in the same order.  Anything that is not a
+// single-row cell sitting on a row is left where it is.
+pub fn optimize<F>(lp: &LegalProblem, legalization: &Vec<LegalPosition>, cost: &F) -> Vec<LegalPosition>
+where
+    F: Fn(&LegalBlock, f32) -> f32,
+{
+    optimize_around(lp, legalization, &Vec::new(), cost)
+}
+
+// The same, stepping around fixed positions that are not part of the
+// legalization (and are not returned)
+pub fn optimize_around<F>(
+    lp: &LegalProblem,
+    legalization: &Vec<LegalPosition>,
+    obstacles: &Vec<LegalPosition>,
+    cost: &F,
+) -> Vec<LegalPosition>
+where
+    F: Fn(&LegalBlock, f32) -> f32,
+{
+    let params = &lp.params;
+    // The cost is charged with the weight of the original block
+    let originals: HashMap<usize, &LegalBlock> = lp.blocks.iter().map(|b| (b.tag, b)).collect();
+    let mut fixed = Vec::new();
+    let mut rows: Vec<Vec<(f32, LegalBlock)>> = vec![Vec::new(); params.grid_y];
+    for pos in legalization {
+        let row = ((pos.y - params.origin_y) / params.step_y).round();
+        let on_row = row >= 0.0
+            && (row as usize) < params.grid_y
+            && (params.origin_y + row * params.step_y - pos.y).abs() < EPSILON
+            && !macros::is_macro(&block_at(pos, &originals), params);
+        if on_row {
+            let block = LegalBlock {
+                x: pos.original_x,
+                y: pos.original_y,
+                ..block_at(pos, &originals)
+            };
+            rows[row as usize].push((pos.x, block));
+        } else {
+            fixed.push(*pos);
+        }
+    }
+
+    let mut all_fixed = fixed.clone();
+    all_fixed.extend(obstacles.iter().copied());
+    let blocked = blocked_sites(params, &all_fixed);
+    let mut positions = fixed;
+    for (row, cells) in rows.iter_mut().enumerate() {
+        // Keep the order they were legalized in
+        cells.sort_by(|a, b| {
+            a.0.partial_cmp(&b.0)
+                .expect("Could not compare")
+                .then(a.1.tag.cmp(&b.1.tag))
+        });
+        let blocks: Vec<LegalBlock> = cells.iter().map(|(_, b)| *b).collect();
+        // If it does not fit on sites, or the table would be too
+        // large, keep the row as it was
+        let placed = if fits_table(&blocks, params) {
+            place_row(&blocks, params, &blocked[row], cost)
+        } else {
+            None
+        };
+        let xs = placed.unwrap_or_else(|| cells.iter().map(|(x, _)| *x).collect());
+        emit_row(row, &blocks, xs, params, &mut positions);
+    }
+    positions
+}
+
+// Legalize with a cost function for the horizontal placement in each
+// row.  Gap-aware Tetris decides the row and order of each cell, and
+// each row is then placed exactly.
+pub fn legalize_cost<F>(lp: &LegalProblem, cost: &F) -> Vec<LegalPosition>
+where
+    F: Fn(&LegalBlock, f32) -> f32,
+{
+    legalize_around(lp, &Vec::new(), cost)
+}
+
+// The same, around fixed positions
+pub fn legalize_around<F>(lp: &LegalProblem, fixed: &Vec<LegalPosition>, cost: &F) -> Vec<LegalPosition>
+where
+    F: Fn(&LegalBlock, f32) -> f32,
+{
+    let assigned = tetris::legalize_gaps(lp, &TetrisConfig::default(), fixed);
+    optimize_around(lp, &assigned, fixed, cost)
+}
+
+// Exact row legalization, minimizing horizontal displacement
+pub fn legalize(lp: &LegalProblem) -> Vec<LegalPosition> {
+    legalize_cost(lp, &displacement)
+}
+
+// Exact row legalization around fixed positions
+pub fn legalize_fixed(lp: &LegalProblem, fixed: &Vec<LegalPosition>) -> Vec<LegalPosition> {
+    legalize_around(lp, fixed, &displacement)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::legalize::legal_block_cmp_x;
+    use crate::legalize::random::Rng;
+
+    // Cheapest cost of placing the blocks in order on the free sites,
+    // trying every start for every block
+    fn brute_force<F>(blocks: &[LegalBlock], params: &LegalParams, blocked: &Vec<bool>, first: usize, cost: &F) -> Option<f32>
+    where
+        F: Fn(&LegalBlock, f32) -> f32,
+    {
+        let Some((block, rest)) = blocks.split_first() else {
+            return Some(0.0);
+        };
+        let width = sites_for(params, block.w);
+        let mut best: Option<f32> = None;
+        for start in first..=params.grid_x.saturating_sub(width) {
+            if blocked[start..start + width].iter().any(|&b| b) {
+                continue;
+            }
+            let x = params.origin_x + start as f32 * params.step_x;
+            if let Some(c) = brute_force(rest, params, blocked, start + width, cost) {
+                let c = c + cost(block, x);
+                if best.is_none_or(|b| c < b) {
+                    best = Some(c);
+                }
+            }
+        }
+        best
+    }
+
+    #[test]
+    fn place_row_matches_brute_force() {
+        let mut rng = Rng::new(45);
+        // Not convex, so a greedy or clustering placer would miss it
+        let bumpy = |block: &LegalBlock, x: f32| (x - block.x).abs().sqrt() * block.w * block.weight;
+        for _ in 0..500 {
+            let params = LegalParams {
+                grid_x: 4 + rng.below(8),
+                grid_y: 1,
+                origin_x: 10.0,
+                origin_y: 0.0,
+                step_x: 2.0,
+                step_y: 10.0,
+                alpha_right: 2.0,
+                alpha_left: 0.5,
+            };
+            let blocked: Vec<bool> = (0..params.grid_x).map(|_| rng.below(5) == 0).collect();
+            let mut blocks: Vec<LegalBlock> = (0..1 + rng.below(4))
+                .map(|tag| LegalBlock {
+                    tag,
+                    x: rng.range(5.0, 35.0),
+                    y: 0.0,
+                    h: 10.0,
+                    w: 2.0 * (1 + rng.below(3)) as f32,
+                    weight: rng.range(0.5, 4.0),
+                    priority: 0,
+                })
+                .collect();
+            blocks.sort_by(legal_block_cmp_x);
+
+            for cost in [&displacement as &dyn Fn(&LegalBlock, f32) -> f32, &bumpy] {
+                let expected = brute_force(&blocks, &params, &blocked, 0, &cost);
+                let found = place_row(&blocks, &params, &blocked, &cost);
+                assert_eq!(found.is_some(), expected.is_some());
+                if let (Some(xs), Some(expected)) = (found, expected) {
+                    // Legal: in order, on free sites, without overlap
+                    let mut end = params.origin_x;
+                    for (block, &x) in blocks.iter().zip(&xs) {
+                        assert!(x >= end - EPSILON);
+                        let site = ((x - params.origin_x) / params.step_x).round() as usize;
+                        assert!(!blocked[site..site + sites_for(&params, block.w)].iter().any(|&b| b));
+                        end = x + block.w;
+                    }
+                    assert!(end <= params.origin_x + params.grid_x as f32 * params.step_x + EPSILON);
+                    let total: f32 = blocks.iter().zip(&xs).map(|(b, &x)| cost(b, x)).sum();
+                    assert!((total - expected).abs() < 1.0e-3, "{} vs {}", total, expected);
+                }
+            }
+        }
+    }
+}
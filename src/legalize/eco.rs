@@ -0,0 +1,311 @@
+// Incremental (ECO) legalization.  Starting from a legal placement,
+// blocks are added, removed or resized, and only the blocks affected
+// are moved; everything else stays exactly where it was.
+//
+// Added and resized blocks first drop into the nearest free gap, with
+// everything else fixed (gap-aware Tetris).  A block that has to go
+// further than the radius to find one makes room nearer by instead:
+// the standard cells in a window around it are shifted along their
+// row, keeping their order, with the minimum total movement (the
+// PlaceRow step of Abacus).  If nothing in the window has room, the
+// window grows.  Macros are never moved.
+
+use super::macros;
+use super::placerow;
+use super::tetris::{self, TetrisConfig};
+use super::{block_at, LegalBlock, LegalParams, LegalPosition, LegalProblem};
+use std::collections::HashMap;
+
+// Position differences smaller than this are rounding noise
+const EPSILON: f32 = 1.0e-3;
+
+#[derive(Clone, Copy)]
+pub enum EcoChange {
+    Add(LegalBlock), // A new block, at its preferred position
+    Remove(usize),   // Tag of a block to take out
+    Resize { tag: usize, w: f32, h: f32 },
+}
+
+#[derive(Clone, Copy)]
+pub struct EcoConfig {
+    pub radius: f32,        // In rows; further than this shifts the neighbours
+    pub window_rows: usize, // Rows above and below the block to try
+    pub window_width: f32,  // Window width, in block widths
+    pub expansions: usize,  // Times the window can double
+    pub tetris: TetrisConfig,
+}
+
+impl Default for EcoConfig {
+    fn default() -> Self {
+        EcoConfig {
+            radius: 2.0,
+            window_rows: 1,
+            window_width: 8.0,
+            expansions: 3,
+            tetris: TetrisConfig::default(),
+        }
+    }
+}
+
+fn distance(block: &LegalBlock, pos: &LegalPosition) -> f32 {
+    (pos.x - block.x).abs() + (pos.y - block.y).abs()
+}
+
+// Place blocks into the gaps left by the fixed positions
+fn place(
+    params: &LegalParams,
+    config: &EcoConfig,
+    blocks: Vec<LegalBlock>,
+    fixed: &Vec<LegalPosition>,
+) -> Vec<LegalPosition> {
+    let lp = LegalProblem {
+        blocks,
+        params: *params,
+    };
+    tetris::legalize_gaps(&lp, &config.tetris, fixed)
+}
+
+// Free intervals of one row, between the obstacles given
+fn free_intervals(params: &LegalParams, mut obstacles: Vec<(f32, f32)>) -> Vec<(f32, f32)> {
+    let core_right = params.origin_x + params.grid_x as f32 * params.step_x;
+    obstacles.sort_by(|a, b| a.0.partial_cmp(&b.0).expect("Could not compare"));
+    let mut intervals = Vec::new();
+    let mut x = params.origin_x;
+    for (a, b) in obstacles {
+        if a - x > EPSILON {
+            intervals.push((x, a.min(core_right)));
+        }
+        x = x.max(b);
+    }
+    if core_right - x > EPSILON {
+        intervals.push((x, core_right));
+    }
+    intervals
+}
+
+// Make room for a block in one row by shifting the standard cells
+// within the window, in their current order.  Returns the cost (the
+// displacement of the block, plus how far the others moved), the
+// indices of the cells moved, and the new positions (block last).
+fn shift_row(
+    params: &LegalParams,
+    originals: &HashMap<usize, &LegalBlock>,
+    block: &LegalBlock,
+    positions: &Vec<LegalPosition>,
+    row: usize,
+    left: f32,
+    right: f32,
+) -> Option<(f32, Vec<usize>, Vec<LegalPosition>)> {
+    let y = params.origin_y + row as f32 * params.step_y;
+    let covers = |p: &LegalPosition| p.y < y + params.step_y - EPSILON && p.y + p.h > y + EPSILON;
+    let movable = |p: &LegalPosition| {
+        (p.y - y).abs() < EPSILON && p.h <= params.step_y + EPSILON && p.x < right && p.x + p.w > left
+    };
+
+    let obstacles = positions
+        .iter()
+        .filter(|p| covers(p) && !movable(p))
+        .map(|p| (p.x, p.x + p.w))
+        .collect();
+
+    let mut best: Option<(f32, Vec<usize>, Vec<LegalPosition>)> = None;
+    for (start, limit) in free_intervals(params, obstacles) {
+        if limit <= left || start >= right {
+            continue;
+        }
+        let mut members: Vec<usize> = (0..positions.len())
+            .filter(|&i| {
+                let p = &positions[i];
+                movable(p) && p.x >= start - EPSILON && p.x + p.w <= limit + EPSILON
+            })
+            .collect();
+        let width: f32 = members.iter().map(|&i| positions[i].w).sum();
+        if width + block.w > limit - start + EPSILON {
+            continue;
+        }
+        members.sort_by(|&a, &b| {
+            positions[a]
+                .x
+                .partial_cmp(&positions[b].x)
+                .expect("Could not compare")
+        });
+
+        // The block goes in among the others by its preferred x
+        let mut row_blocks: Vec<LegalBlock> = members
+            .iter()
+            .map(|&i| LegalBlock {
+                y,
+                ..block_at(&positions[i], originals)
+            })
+            .collect();
+        let at = row_blocks.partition_point(|b| b.x + b.w / 2.0 < block.x + block.w / 2.0);
+        row_blocks.insert(at, *block);
+        let xs = placerow::place_row(&row_blocks, start, limit);
+
+        let mut cost = (xs[at] - block.x).abs() + (y - block.y).abs();
+        let mut moved = Vec::new();
+        for (k, &x) in xs.iter().enumerate() {
+            if k == at {
+                continue;
+            }
+            let i = members[if k < at { k } else { k - 1 }];
+            cost += (x - positions[i].x).abs();
+            let mut pos = positions[i];
+            pos.x = x;
+            moved.push(pos);
+        }
+        moved.push(LegalPosition {
+            block_tag: block.tag,
+            x: xs[at],
+            y,
+            h: block.h,
+            w: block.w,
+            original_x: block.x,
+            original_y: block.y,
+        });
+        if best.as_ref().is_none_or(|b| cost < b.0) {
+            best = Some((cost, members, moved));
+        }
+    }
+    best
+}
+
+// Find room for a block near where it wants to be by shifting the
+// cells around it, in a window that grows until the block lands
+// within the radius.
+fn shift_window(
+    params: &LegalParams,
+    originals: &HashMap<usize, &LegalBlock>,
+    config: &EcoConfig,
+    block: &LegalBlock,
+    positions: &Vec<LegalPosition>,
+) -> Option<(f32, Vec<usize>, Vec<LegalPosition>)> {
+    let radius = config.radius * params.step_y;
+    let row = ((block.y - params.origin_y) / params.step_y).round().max(0.0) as usize;
+    let mut best: Option<(f32, Vec<usize>, Vec<LegalPosition>)> = None;
+    let mut scale = 1.0;
+    for _ in 0..=config.expansions {
+        let half_width = block.w * config.window_width * scale / 2.0;
+        let left = block.x + block.w / 2.0 - half_width;
+        let right = block.x + block.w / 2.0 + half_width;
+        let rows = (config.window_rows as f32 * scale).ceil() as usize;
+        let low = row.saturating_sub(rows);
+        let high = (row + rows).min(params.grid_y.saturating_sub(1));
+        for r in low..=high {
+            if let Some(found) = shift_row(params, originals, block, positions, r, left, right) {
+                if best.as_ref().is_none_or(|b| found.0 < b.0) {
+                    best = Some(found);
+                }
+            }
+        }
+        // The block is the last of the positions
+        let landed = best.as_ref().and_then(|b| b.2.last());
+        if landed.is_some_and(|p| distance(block, p) <= radius) {
+            break;
+        }
+        scale *= 2.0;
+    }
+    best
+}
+
+// Apply the changes to a legal placement of the problem.  Blocks that
+// were not changed keep their positions unless they are in the
+// neighbourhood of a changed block that could not find space nearby.
+// Blocks keep the weight and priority they have in the problem (or
+// in the change that added them).
+pub fn legalize_eco(
+    lp: &LegalProblem,
+    placement: &Vec<LegalPosition>,
+    changes: &Vec<EcoChange>,
+    config: &EcoConfig,
+) -> Vec<LegalPosition> {
+    let params = &lp.params;
+    let mut originals: HashMap<usize, &LegalBlock> = lp.blocks.iter().map(|b| (b.tag, b)).collect();
+    for change in changes {
+        if let EcoChange::Add(block) = change {
+            originals.insert(block.tag, block);
+        }
+    }
+    let mut positions = placement.clone();
+    // Blocks to place, with the original position to report them from
+    let mut pending: Vec<(LegalBlock, f32, f32)> = Vec::new();
+
+    for change in changes {
+        match *change {
+            EcoChange::Add(block) => pending.push((block, block.x, block.y)),
+            EcoChange::Remove(tag) => {
+                positions.retain(|p| p.block_tag != tag);
+                pending.retain(|(b, _, _)| b.tag != tag);
+            }
+            EcoChange::Resize { tag, w, h } => {
+                if let Some((block, _, _)) = pending.iter_mut().find(|(b, _, _)| b.tag == tag) {
+                    block.w = w;
+                    block.h = h;
+                } else if let Some(i) = positions.iter().position(|p| p.block_tag == tag) {
+                    let pos = positions.remove(i);
+                    let block = LegalBlock {
+                        h,
+                        w,
+                        ..block_at(&pos, &originals)
+                    };
+                    pending.push((block, pos.original_x, pos.original_y));
+                } else {
+                    println!("**** ECO resize of unknown block {}", tag);
+                }
+            }
+        }
+    }
+
+    // Everything into the nearest gaps first
+    let blocks: Vec<LegalBlock> = pending.iter().map(|(b, _, _)| *b).collect();
+    let placed = place(params, config, blocks, &positions);
+    let wanted: HashMap<usize, (LegalBlock, f32, f32)> = pending.iter().map(|p| (p.0.tag, *p)).collect();
+
+    let radius = config.radius * params.step_y;
+    let mut far = Vec::new();
+    for pos in placed {
+        let (block, _, _) = wanted[&pos.block_tag];
+        if distance(&block, &pos) <= radius {
+            positions.push(pos);
+        } else {
+            far.push((block, pos));
+        }
+    }
+
+    // Then shift the neighbours of anything that went too far, if
+    // that moves things less than taking the gap.  Shifting can fill
+    // the gaps, so anything left over goes into what remains at the end.
+    let mut leftover = Vec::new();
+    for (block, gap) in far {
+        let gap_cost = distance(&block, &gap);
+        #[cfg(feature = "ldbg")]
+        println!("ECO block {} went {} to a gap", block.tag, gap_cost);
+        let shifted = if macros::is_macro(&block, params) {
+            None
+        } else {
+            shift_window(params, &originals, config, &block, &positions)
+        };
+        match shifted {
+            Some((cost, members, moved)) if cost < gap_cost => {
+                let mut members = members;
+                members.sort_unstable();
+                for i in members.into_iter().rev() {
+                    positions.swap_remove(i);
+                }
+                positions.extend(moved);
+            }
+            _ => leftover.push(block),
+        }
+    }
+    let placed = place(params, config, leftover, &positions);
+    positions.extend(placed);
+
+    // Report changed blocks from their original positions
+    for pos in &mut positions {
+        if let Some(&(_, x, y)) = wanted.get(&pos.block_tag) {
+            pos.original_x = x;
+            pos.original_y = y;
+        }
+    }
+    positions
+}
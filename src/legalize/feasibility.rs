@@ -0,0 +1,195 @@
+// Feasibility analysis, before legalizing.  Compare the total block
+// area against the core (less anything blocked off by fixed blocks),
+// overall and for horizontal bands of rows, and check that the
+// widest block fits in a row and the tallest fits in the core.  The
+// legalizers assume all of this, and otherwise fail in odd ways.
+
+use super::{LegalError, LegalPosition, LegalProblem};
+use std::fmt;
+
+pub struct RegionUse {
+    pub origin_y: f32,
+    pub rows: usize,
+    pub area: f32,     // Block area that would like to be in the region
+    pub capacity: f32, // Core area in the region, less blockages
+}
+
+impl RegionUse {
+    pub fn utilization(&self) -> f32 {
+        if self.capacity > 0.0 {
+            self.area / self.capacity
+        } else if self.area > 0.0 {
+            f32::INFINITY
+        } else {
+            0.0
+        }
+    }
+}
+
+pub struct Feasibility {
+    pub area: f32,     // Total area of the blocks to legalize
+    pub capacity: f32, // Core area, less blockages
+    pub blocked: f32,  // Core area taken by fixed blocks
+    pub regions: Vec<RegionUse>,
+    pub widest: Option<(usize, f32)>, // Tag and width
+    pub row_length: f32,
+    pub tallest: Option<(usize, f32)>, // Tag and height
+    pub core_height: f32,
+}
+
+// Area of a rectangle that falls between y_low and y_high, and inside
+// the core horizontally
+fn clipped_area(lp: &LegalProblem, f: &LegalPosition, y_low: f32, y_high: f32) -> f32 {
+    let left = lp.params.origin_x;
+    let right = left + lp.params.grid_x as f32 * lp.params.step_x;
+    let w = (f.x + f.w).min(right) - f.x.max(left);
+    let h = (f.y + f.h).min(y_high) - f.y.max(y_low);
+    w.max(0.0) * h.max(0.0)
+}
+
+pub fn analyze(lp: &LegalProblem, fixed: &Vec<LegalPosition>, regions: usize) -> Feasibility {
+    let params = &lp.params;
+    let row_length = params.grid_x as f32 * params.step_x;
+    let core_height = params.grid_y as f32 * params.step_y;
+
+    let mut report = Feasibility {
+        area: lp.area(),
+        capacity: 0.0,
+        blocked: 0.0,
+        regions: Vec::new(),
+        widest: None,
+        row_length,
+        tallest: None,
+        core_height,
+    };
+
+    let top = params.origin_y + core_height;
+    for f in fixed {
+        report.blocked += clipped_area(lp, f, params.origin_y, top);
+    }
+    report.capacity = row_length * core_height - report.blocked;
+
+    for block in &lp.blocks {
+        if report.widest.map_or(true, |(_, w)| block.w > w) {
+            report.widest = Some((block.tag, block.w));
+        }
+        if report.tallest.map_or(true, |(_, h)| block.h > h) {
+            report.tallest = Some((block.tag, block.h));
+        }
+    }
+
+    for band in lp.split_rows(regions) {
+        let y_low = band.params.origin_y;
+        let y_high = y_low + band.params.grid_y as f32 * params.step_y;
+        let mut blocked = 0.0;
+        for f in fixed {
+            blocked += clipped_area(lp, f, y_low, y_high);
+        }
+        report.regions.push(RegionUse {
+            origin_y: y_low,
+            rows: band.params.grid_y,
+            area: band.area(),
+            capacity: row_length * (y_high - y_low) - blocked,
+        });
+    }
+
+    report
+}
+
+impl Feasibility {
+    pub fn utilization(&self) -> f32 {
+        if self.capacity > 0.0 {
+            self.area / self.capacity
+        } else {
+            f32::INFINITY
+        }
+    }
+
+    // Problems that no legalizer can get around
+    pub fn errors(&self) -> Vec<LegalError> {
+        let mut errors = Vec::new();
+        if self.row_length <= 0.0 || self.core_height <= 0.0 {
+            errors.push(LegalError::EmptyCore);
+            return errors;
+        }
+        if self.area > self.capacity {
+            errors.push(LegalError::OverCapacity {
+                area: self.area,
+                capacity: self.capacity,
+            });
+        }
+        if let Some((tag, w)) = self.widest {
+            if w > self.row_length {
+                errors.push(LegalError::TooWide {
+                    tag,
+                    w,
+                    row_length: self.row_length,
+                });
+            }
+        }
+        if let Some((tag, h)) = self.tallest {
+            if h > self.core_height {
+                errors.push(LegalError::TooTall {
+                    tag,
+                    h,
+                    core_height: self.core_height,
+                });
+            }
+        }
+        errors
+    }
+
+    // Regions that are over capacity -- blocks will have to move a
+    // long way out of them, but it can be done
+    pub fn warnings(&self) -> Vec<LegalError> {
+        let mut warnings = Vec::new();
+        for region in &self.regions {
+            if region.area > region.capacity {
+                warnings.push(LegalError::RegionOverCapacity {
+                    origin_y: region.origin_y,
+                    area: region.area,
+                    capacity: region.capacity,
+                });
+            }
+        }
+        warnings
+    }
+
+    pub fn check(&self) -> Result<(), LegalError> {
+        match self.errors().into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Feasibility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Block area {:.1}  capacity {:.1} ({:.1} blocked)  utilization {:.1}%",
+            self.area,
+            self.capacity,
+            self.blocked,
+            self.utilization() * 100.0
+        )?;
+        for region in &self.regions {
+            writeln!(
+                f,
+                "  Rows from {:.1}: {} rows  area {:.1}  capacity {:.1}  utilization {:.1}%",
+                region.origin_y,
+                region.rows,
+                region.area,
+                region.capacity,
+                region.utilization() * 100.0
+            )?;
+        }
+        if let Some((tag, w)) = self.widest {
+            writeln!(f, "Widest block {} width {:.1}, row length {:.1}", tag, w, self.row_length)?;
+        }
+        if let Some((tag, h)) = self.tallest {
+            write!(f, "Tallest block {} height {:.1}, core height {:.1}", tag, h, self.core_height)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+// Legalization around fixed blocks, with any legalizer.  The
+// legalizer places the movable blocks (it may or may not know about
+// the fixed ones -- gap-aware Tetris does, the frontier legalizers do
+// not), and then anything that lands on a fixed block, overlaps
+// another block or sticks out of the core is ripped up and dropped
+// into the nearest free gap (gap-aware Tetris), with the fixed blocks
+// and every other movable block held where they are.  The fixed
+// blocks themselves never move, and are not in the result.
+
+use super::tetris::{self, TetrisConfig};
+use super::{block_at, LegalBlock, LegalPosition, LegalProblem};
+use std::collections::HashMap;
+
+// Rip up the positions that overlap something (including the fixed
+// blocks) or are outside the core, and put them into the nearest gap
+// that is left
+pub fn repair(
+    lp: &LegalProblem,
+    positions: Vec<LegalPosition>,
+    fixed: &Vec<LegalPosition>,
+    config: &TetrisConfig,
+) -> Vec<LegalPosition> {
+    let n = positions.len();
+    let mut all = positions;
+    all.extend(fixed.iter().copied());
+    let bad = super::find_violations(&all, &lp.params);
+    all.truncate(n);
+    if !bad[..n].contains(&true) {
+        return all;
+    }
+
+    let blocks: HashMap<usize, &LegalBlock> = lp.blocks.iter().map(|b| (b.tag, b)).collect();
+    let mut kept = Vec::new();
+    let mut ripped = LegalProblem {
+        blocks: Vec::new(),
+        params: lp.params,
+    };
+    for (pos, bad) in all.into_iter().zip(bad) {
+        if bad {
+            // Keep the weight and priority of the block
+            ripped.blocks.push(LegalBlock {
+                x: pos.original_x,
+                y: pos.original_y,
+                ..block_at(&pos, &blocks)
+            });
+        } else {
+            kept.push(pos);
+        }
+    }
+    #[cfg(feature = "ldbg")]
+    println!("Repair of {} blocks", ripped.blocks.len());
+
+    let mut obstacles = kept.clone();
+    obstacles.extend(fixed.iter().copied());
+    let repaired = tetris::legalize_gaps(&ripped, config, &obstacles);
+    kept.extend(repaired);
+    kept
+}
+
+// Legalize the movable blocks with a legalizer that does not know
+// about fixed blocks, then move whatever conflicts with them out of
+// their way
+pub fn legalize<F>(
+    lp: &LegalProblem,
+    fixed: &Vec<LegalPosition>,
+    config: &TetrisConfig,
+    legalizer: &F,
+) -> Vec<LegalPosition>
+where
+    F: Fn(&LegalProblem) -> Vec<LegalPosition>,
+{
+    let positions = legalizer(lp);
+    if fixed.is_empty() {
+        return positions;
+    }
+    repair(lp, positions, fixed, config)
+}
@@ -0,0 +1,191 @@
+// Min-cost flow, for spreading and for the displacement lower bound.
+// Capacities, costs and flows are real numbers, since what flows is
+// usually cell area or width.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+
+// Flow smaller than this is rounding noise
+pub const EPSILON: f64 = 1.0e-6;
+
+pub struct Edge {
+    pub to: usize,
+    pub capacity: f64,
+    pub cost: f64,
+    pub flow: f64,
+    pub reverse: usize, // Index of the reverse edge in the adjacency of `to`
+}
+
+// Min-cost flow by successive shortest paths, with Dijkstra on reduced
+// costs (all of the costs are non-negative to begin with)
+pub struct FlowGraph {
+    pub edges: Vec<Vec<Edge>>,
+}
+
+#[derive(PartialEq)]
+struct Visit(f64, usize);
+
+impl Eq for Visit {}
+
+impl Ord for Visit {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Smallest distance first
+        other.0.partial_cmp(&self.0).unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Visit {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl FlowGraph {
+    pub fn new(n: usize) -> FlowGraph {
+        FlowGraph {
+            edges: (0..n).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    pub fn add_edge(&mut self, from: usize, to: usize, capacity: f64, cost: f64) {
+        let forward = self.edges[from].len();
+        let reverse = self.edges[to].len();
+        self.edges[from].push(Edge {
+            to,
+            capacity,
+            cost,
+            flow: 0.0,
+            reverse,
+        });
+        self.edges[to].push(Edge {
+            to: from,
+            capacity: 0.0,
+            cost: -cost,
+            flow: 0.0,
+            reverse: forward,
+        });
+    }
+
+    // Residual edge with zero reduced cost, on a shortest path
+    fn admissible(&self, u: usize, k: usize, potential: &Vec<f64>) -> bool {
+        let e = &self.edges[u][k];
+        e.capacity - e.flow > EPSILON && (e.cost + potential[u] - potential[e.to]).abs() < EPSILON
+    }
+
+    // Shortest distances from the source with the reduced costs, which
+    // then update the potentials.  Returns false if the sink cannot be
+    // reached.
+    fn shortest_paths(&self, source: usize, sink: usize, potential: &mut Vec<f64>) -> bool {
+        let n = self.edges.len();
+        let mut dist = vec![f64::MAX; n];
+        let mut heap = BinaryHeap::new();
+        dist[source] = 0.0;
+        heap.push(Visit(0.0, source));
+        while let Some(Visit(d, u)) = heap.pop() {
+            if d > dist[u] {
+                continue;
+            }
+            for e in &self.edges[u] {
+                if e.capacity - e.flow <= EPSILON {
+                    continue;
+                }
+                let nd = d + e.cost + potential[u] - potential[e.to];
+                if nd < dist[e.to] - EPSILON {
+                    dist[e.to] = nd;
+                    heap.push(Visit(nd, e.to));
+                }
+            }
+        }
+        if dist[sink] == f64::MAX {
+            return false;
+        }
+        for v in 0..n {
+            potential[v] += dist[v].min(dist[sink]);
+        }
+        true
+    }
+
+    // Push flow from u down the levels along admissible edges, Dinic
+    // style.  Returns the flow pushed.
+    #[allow(clippy::too_many_arguments)]
+    fn augment(
+        &mut self,
+        u: usize,
+        sink: usize,
+        limit: f64,
+        level: &Vec<usize>,
+        next: &mut Vec<usize>,
+        potential: &Vec<f64>,
+        cost: &mut f64,
+    ) -> f64 {
+        if u == sink {
+            return limit;
+        }
+        while next[u] < self.edges[u].len() {
+            let k = next[u];
+            let to = self.edges[u][k].to;
+            if level[to] == level[u] + 1 && self.admissible(u, k, potential) {
+                let residual = self.edges[u][k].capacity - self.edges[u][k].flow;
+                let pushed = self.augment(to, sink, limit.min(residual), level, next, potential, cost);
+                if pushed > EPSILON {
+                    let reverse = self.edges[u][k].reverse;
+                    self.edges[u][k].flow += pushed;
+                    self.edges[to][reverse].flow -= pushed;
+                    *cost += pushed * self.edges[u][k].cost;
+                    return pushed;
+                }
+            }
+            next[u] += 1;
+        }
+        0.0
+    }
+
+    // Push as much flow as possible from source to sink, at least cost
+    // (primal-dual: shortest paths set the potentials, then a blocking
+    // flow fills every path that is now shortest).  Returns the flow
+    // and its cost.
+    pub fn solve(&mut self, source: usize, sink: usize) -> (f64, f64) {
+        let n = self.edges.len();
+        let mut potential = vec![0.0; n];
+        let mut total_flow = 0.0;
+        let mut total_cost = 0.0;
+        while self.shortest_paths(source, sink, &mut potential) {
+            loop {
+                // Level the nodes by hops from the source
+                let mut level = vec![usize::MAX; n];
+                let mut queue = VecDeque::new();
+                level[source] = 0;
+                queue.push_back(source);
+                while let Some(u) = queue.pop_front() {
+                    for k in 0..self.edges[u].len() {
+                        let to = self.edges[u][k].to;
+                        if level[to] == usize::MAX && self.admissible(u, k, &potential) {
+                            level[to] = level[u] + 1;
+                            queue.push_back(to);
+                        }
+                    }
+                }
+                if level[sink] == usize::MAX {
+                    break;
+                }
+                let mut next = vec![0; n];
+                loop {
+                    let pushed = self.augment(
+                        source,
+                        sink,
+                        f64::MAX,
+                        &level,
+                        &mut next,
+                        &potential,
+                        &mut total_cost,
+                    );
+                    if pushed <= EPSILON {
+                        break;
+                    }
+                    total_flow += pushed;
+                }
+            }
+        }
+        (total_flow, total_cost)
+    }
+}
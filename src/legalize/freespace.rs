@@ -0,0 +1,143 @@
+// Free space in each row of the core, kept as a sorted list of
+// non-overlapping (start, end) intervals.  Anything placed or fixed
+// is carved out of the rows it covers, so whitespace left behind
+// stays available to later blocks.
+
+use super::LegalParams;
+use std::ops::Range;
+
+// Slivers of free space narrower than this are dropped
+const EPSILON: f32 = 1.0e-3;
+
+#[derive(Clone)]
+pub struct FreeSpace {
+    pub origin_y: f32,
+    pub step_y: f32,
+    pub rows: Vec<Vec<(f32, f32)>>,
+}
+
+impl FreeSpace {
+    pub fn new(params: &LegalParams) -> FreeSpace {
+        let left = params.origin_x;
+        let right = params.origin_x + params.grid_x as f32 * params.step_x;
+        FreeSpace {
+            origin_y: params.origin_y,
+            step_y: params.step_y,
+            rows: vec![vec![(left, right)]; params.grid_y],
+        }
+    }
+
+    // Rows touched by anything between y and y + h
+    pub fn row_span(&self, y: f32, h: f32) -> Range<usize> {
+        let low = ((y - self.origin_y) / self.step_y + EPSILON).floor().max(0.0) as usize;
+        let high = ((y + h - self.origin_y) / self.step_y - EPSILON).ceil().max(0.0) as usize;
+        low.min(self.rows.len())..high.min(self.rows.len())
+    }
+
+    // Remove x..x+w from each of the rows
+    pub fn carve(&mut self, rows: Range<usize>, x: f32, w: f32) {
+        let end = x + w;
+        for r in rows {
+            let mut remaining = Vec::with_capacity(self.rows[r].len() + 1);
+            for &(a, b) in &self.rows[r] {
+                if b <= x || a >= end {
+                    remaining.push((a, b));
+                    continue;
+                }
+                if x - a > EPSILON {
+                    remaining.push((a, x));
+                }
+                if b - end > EPSILON {
+                    remaining.push((end, b));
+                }
+            }
+            self.rows[r] = remaining;
+        }
+    }
+
+    // Remove a fixed rectangle, whatever rows it happens to cover
+    pub fn carve_rect(&mut self, x: f32, y: f32, w: f32, h: f32) {
+        let rows = self.row_span(y, h);
+        self.carve(rows, x, w);
+    }
+
+    // Free intervals common to all of the rows
+    fn common(&self, rows: Range<usize>) -> Vec<(f32, f32)> {
+        let mut rows = rows.into_iter();
+        let mut result = match rows.next() {
+            Some(r) => self.rows[r].clone(),
+            None => return Vec::new(),
+        };
+        for r in rows {
+            let other = &self.rows[r];
+            let mut merged = Vec::new();
+            let mut i = 0;
+            let mut j = 0;
+            while i < result.len() && j < other.len() {
+                let a = result[i].0.max(other[j].0);
+                let b = result[i].1.min(other[j].1);
+                if b - a > EPSILON {
+                    merged.push((a, b));
+                }
+                if result[i].1 < other[j].1 {
+                    i += 1;
+                } else {
+                    j += 1;
+                }
+            }
+            result = merged;
+        }
+        result
+    }
+
+    // Position closest to x where a block of width w fits in all of
+    // the rows, if there is one.
+    pub fn nearest_fit(&self, rows: Range<usize>, x: f32, w: f32) -> Option<f32> {
+        if rows.len() == 1 {
+            // Skip the copy for the common single-row case
+            return nearest_in(&self.rows[rows.start], x, w);
+        }
+        nearest_in(&self.common(rows), x, w)
+    }
+
+    // Total free space in a row
+    pub fn free(&self, row: usize) -> f32 {
+        self.rows[row].iter().map(|(a, b)| b - a).sum()
+    }
+}
+
+fn nearest_in(intervals: &[(f32, f32)], x: f32, w: f32) -> Option<f32> {
+    // Start from the first interval that ends past x, and work
+    // outward in both directions until nothing can be closer.
+    let start = intervals.partition_point(|&(_, b)| b <= x);
+    let mut best: Option<f32> = None;
+    let mut best_dist = f32::MAX;
+
+    for &(a, b) in intervals[start..].iter() {
+        if a - x >= best_dist {
+            break;
+        }
+        if b - a + EPSILON >= w {
+            let pos = x.max(a).min(b - w).max(a);
+            let d = (pos - x).abs();
+            if d < best_dist {
+                best = Some(pos);
+                best_dist = d;
+            }
+        }
+    }
+    for &(a, b) in intervals[..start].iter().rev() {
+        if x - (b - w) >= best_dist {
+            break;
+        }
+        if b - a + EPSILON >= w {
+            let pos = x.max(a).min(b - w).max(a);
+            let d = (pos - x).abs();
+            if d < best_dist {
+                best = Some(pos);
+                best_dist = d;
+            }
+        }
+    }
+    best
+}
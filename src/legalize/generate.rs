@@ -0,0 +1,239 @@
+// Synthetic benchmarks.  A core of rows and sites is filled, up to a
+// target utilization, with standard cells at random preferred
+// positions (as if from a global placer), drawn from a width
+// distribution.  Some of the cells can be several rows tall, some
+// macros can be added, part of the cells can be pulled into clustered
+// hotspots, and fixed blockages can be carved out of the core.
+// Everything comes from the seed, so a problem that shows up a bug can
+// be made again from its config.
+//
+// Blockages are fixed positions, not blocks of the problem; they are
+// saved marked "fixed" (LegalProblem::save_fixed), and load_fixed
+// gives them back for the legalizers to work around.
+
+use super::random::Rng;
+use super::{LegalBlock, LegalParams, LegalPosition, LegalProblem};
+use std::str::FromStr;
+
+// Attempts at finding a free spot for each blockage
+const PLACE_ATTEMPTS: usize = 100;
+
+// How standard cell widths, in sites, are drawn
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WidthDistribution {
+    Uniform(f32, f32),   // Between a minimum and a maximum
+    LogNormal(f32, f32), // Median, and sigma of the log
+}
+
+impl FromStr for WidthDistribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pair = |s: &str| {
+            let (a, b) = s.split_once(':')?;
+            Some((a.parse::<f32>().ok()?, b.parse::<f32>().ok()?))
+        };
+        if s == "uniform" {
+            return Ok(WidthDistribution::Uniform(2.0, 20.0));
+        }
+        if s == "lognormal" {
+            return Ok(WidthDistribution::LogNormal(6.0, 0.5));
+        }
+        if let Some((low, high)) = s.strip_prefix("uniform:").and_then(pair) {
+            return Ok(WidthDistribution::Uniform(low, high));
+        }
+        if let Some((median, sigma)) = s.strip_prefix("lognormal:").and_then(pair) {
+            return Ok(WidthDistribution::LogNormal(median, sigma));
+        }
+        Err(format!(
+            "unknown width distribution {} (uniform:<min>:<max>, lognormal:<median>:<sigma>)",
+            s
+        ))
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct GenerateConfig {
+    pub seed: u64,
+    pub rows: usize,
+    pub sites: usize,                  // Sites in each row
+    pub site_width: f32,               // Step in x
+    pub row_height: f32,               // Step in y
+    pub utilization: f32,              // Block area over the core area left by blockages
+    pub widths: WidthDistribution,     // Standard cell widths, in sites
+    pub multi_row: f32,                // Fraction of cells two or three rows tall
+    pub macros: usize,                 // Number of macros
+    pub macro_rows: (usize, usize),    // Smallest and largest macro height, in rows
+    pub hotspots: usize,               // Number of clustered hotspots
+    pub hotspot_fraction: f32,         // Fraction of the cells in the hotspots
+    pub hotspot_radius: f32,           // Spread of each hotspot, in row heights
+    pub blockages: usize,              // Number of fixed blockages
+    pub blockage_rows: (usize, usize), // Smallest and largest blockage height, in rows
+}
+
+impl Default for GenerateConfig {
+    fn default() -> Self {
+        GenerateConfig {
+            seed: 1,
+            rows: 50,
+            sites: 800,
+            site_width: 1.0,
+            row_height: 16.0,
+            utilization: 0.7,
+            widths: WidthDistribution::LogNormal(6.0, 0.5),
+            multi_row: 0.0,
+            macros: 0,
+            macro_rows: (4, 12),
+            hotspots: 0,
+            hotspot_fraction: 0.3,
+            hotspot_radius: 4.0,
+            blockages: 0,
+            blockage_rows: (2, 6),
+        }
+    }
+}
+
+pub struct Generated {
+    pub problem: LegalProblem,     // The blocks to legalize
+    pub fixed: Vec<LegalPosition>, // The blockages
+}
+
+// Whole number between low and high (inclusive)
+fn between(rng: &mut Rng, low: usize, high: usize) -> usize {
+    low.min(high) + rng.below(high.max(low) - low.min(high) + 1)
+}
+
+fn overlaps(a: &LegalPosition, b: &LegalPosition) -> bool {
+    a.x < b.x + b.w && b.x < a.x + a.w && a.y < b.y + b.h && b.y < a.y + a.h
+}
+
+pub fn generate(config: &GenerateConfig) -> Generated {
+    let mut rng = Rng::new(config.seed);
+    let params = LegalParams {
+        grid_x: config.sites,
+        grid_y: config.rows,
+        origin_x: 0.0,
+        origin_y: 0.0,
+        step_x: config.site_width,
+        step_y: config.row_height,
+        alpha_right: 2.0,
+        alpha_left: 0.5,
+    };
+    let core_w = config.sites as f32 * config.site_width;
+    let core_h = config.rows as f32 * config.row_height;
+    let mut blocks = Vec::new();
+    if config.rows == 0 || config.sites == 0 {
+        return Generated {
+            problem: LegalProblem { blocks, params },
+            fixed: Vec::new(),
+        };
+    }
+
+    // Blockages, on rows and sites, not overlapping each other
+    let mut fixed: Vec<LegalPosition> = Vec::new();
+    for _ in 0..config.blockages {
+        for _attempt in 0..PLACE_ATTEMPTS {
+            let rows = between(&mut rng, config.blockage_rows.0, config.blockage_rows.1).min(config.rows);
+            let h = rows as f32 * config.row_height;
+            let sites = ((h * rng.range(0.5, 2.0)) / config.site_width).round().max(1.0) as usize;
+            let sites = sites.min(config.sites);
+            let pos = LegalPosition {
+                block_tag: fixed.len(),
+                x: rng.below(config.sites - sites + 1) as f32 * config.site_width,
+                y: rng.below(config.rows - rows + 1) as f32 * config.row_height,
+                h,
+                w: sites as f32 * config.site_width,
+                original_x: 0.0,
+                original_y: 0.0,
+            };
+            if fixed.iter().all(|f| !overlaps(f, &pos)) {
+                let pos = LegalPosition {
+                    original_x: pos.x,
+                    original_y: pos.y,
+                    ..pos
+                };
+                fixed.push(pos);
+                break;
+            }
+        }
+    }
+    if fixed.len() < config.blockages {
+        println!("**** Only room for {} of {} blockages", fixed.len(), config.blockages);
+    }
+    let blocked: f32 = fixed.iter().map(|f| f.w * f.h).sum();
+    let budget = config.utilization * (core_w * core_h - blocked);
+    let mut area = 0.0;
+
+    // Somewhere in the core, for a block of this size
+    let anywhere = |rng: &mut Rng, w: f32, h: f32| {
+        (rng.range(0.0, (core_w - w).max(0.0)), rng.range(0.0, (core_h - h).max(0.0)))
+    };
+
+    // Macros, with aspect ratios between 1:2 and 2:1
+    for _ in 0..config.macros {
+        let rows = between(&mut rng, config.macro_rows.0, config.macro_rows.1).min(config.rows);
+        let h = rows as f32 * config.row_height;
+        let w = ((h * rng.range(0.5, 2.0)) / config.site_width).round().max(1.0) * config.site_width;
+        let w = w.min(core_w);
+        let (x, y) = anywhere(&mut rng, w, h);
+        area += w * h;
+        blocks.push(LegalBlock {
+            tag: blocks.len(),
+            x,
+            y,
+            h,
+            w,
+            weight: 1.0,
+            priority: 0,
+        });
+    }
+    if area > budget {
+        println!("**** Macros alone are over the target utilization");
+    }
+
+    // Hotspot centers
+    let centers: Vec<(f32, f32)> = (0..config.hotspots).map(|_| anywhere(&mut rng, 0.0, 0.0)).collect();
+    let spread = config.hotspot_radius * config.row_height;
+
+    // Standard cells, until the area is used up
+    while area < budget {
+        let sites = match config.widths {
+            WidthDistribution::Uniform(low, high) => rng.range(low, high + 1.0).floor(),
+            WidthDistribution::LogNormal(median, sigma) => (median * (sigma * rng.normal()).exp()).round(),
+        };
+        let w = sites.clamp(1.0, config.sites as f32) * config.site_width;
+        let rows = if rng.uniform() < config.multi_row {
+            between(&mut rng, 2, 3).min(config.rows)
+        } else {
+            1
+        };
+        let h = rows as f32 * config.row_height;
+        let (x, y) = if !centers.is_empty() && rng.uniform() < config.hotspot_fraction {
+            let (cx, cy) = centers[rng.below(centers.len())];
+            let x = cx + spread * rng.normal() - w / 2.0;
+            let y = cy + spread * rng.normal() - h / 2.0;
+            (x.clamp(0.0, (core_w - w).max(0.0)), y.clamp(0.0, (core_h - h).max(0.0)))
+        } else {
+            anywhere(&mut rng, w, h)
+        };
+        area += w * h;
+        blocks.push(LegalBlock {
+            tag: blocks.len(),
+            x,
+            y,
+            h,
+            w,
+            weight: 1.0,
+            priority: 0,
+        });
+    }
+
+    // Blocks are tagged by their index; the blockages come after them
+    for (i, f) in fixed.iter_mut().enumerate() {
+        f.block_tag = blocks.len() + i;
+    }
+    Generated {
+        problem: LegalProblem { blocks, params },
+        fixed,
+    }
+}
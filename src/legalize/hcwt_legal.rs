@@ -1,7 +1,12 @@
 use bookshelf_r::bookshelf::BookshelfCircuit;
 use hcwt_r;
 
-use super::{LegalBlock, LegalParams, LegalPosition, LegalProblem};
+use super::macros;
+use super::placerow;
+// The block orderings used to live here
+pub use super::{legal_block_cmp_x, legal_block_cmp_y};
+use super::{LegalBlock, LegalParams, LegalPosition, LegalProblem, Metrics};
+use std::str::FromStr;
 
 /*pub fn legalize(lp: &LegalProblem) -> Vec<LegalPosition> {
     println!("HCWT placement legalizer");
@@ -27,24 +32,101 @@ use std::collections::BinaryHeap;
 
 use std::cmp::Ordering;
 
-pub fn legal_block_cmp_y(a: &LegalBlock, b: &LegalBlock) -> Ordering {
-    if a.y < b.y {
-        return Ordering::Less;
-    }
-    if a.y > b.y {
-        return Ordering::Greater;
-    }
-    Ordering::Equal
+// Which HCwT formulation to run.  The mixed path handles macros by
+// splitting rows into pools between them; the standard-cell path
+// fills whole rows, rebalancing the row target as it goes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HcwtMode {
+    Mixed,    // Always the mixed path
+    Standard, // Standard-cell path, unless there are macros
+    Best,     // Without macros, run both and keep the lower displacement
 }
 
-pub fn legal_block_cmp_x(a: &LegalBlock, b: &LegalBlock) -> Ordering {
-    if a.x < b.x {
-        return Ordering::Less;
+impl FromStr for HcwtMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mixed" => Ok(HcwtMode::Mixed),
+            "standard" => Ok(HcwtMode::Standard),
+            "best" => Ok(HcwtMode::Best),
+            _ => Err(format!("unknown HCwT mode {} (mixed, standard, best)", s)),
+        }
     }
-    if a.x > b.x {
-        return Ordering::Greater;
+}
+
+// Tuning for the HCwT legalizer.  The defaults are the values that
+// were originally hard-coded into the row pairs.
+#[derive(Copy, Clone, Debug)]
+pub struct HcwtConfig {
+    // Cost of a block in the upper row, relative to the lower row --
+    // slightly less, as the upper row will be revisited
+    pub upper_weight: f32,
+    // Weight of horizontal movement in the upper row, where the x
+    // order is discarded anyway
+    pub upper_horizontal_weight: f32,
+    // Upper row horizontal weight in the last row pairs, where there
+    // is no chance to recover (standard-cell path)
+    pub top_horizontal_weight: f32,
+    // A pool that can take all of its blocks within this much of its
+    // target length is packed without solving
+    pub hard_max_slack: f32,
+    // ...and in the last row, this much, so that nothing is left over
+    pub last_row_slack: f32,
+    // Largest allowed difference between the lengths of neighbouring
+    // rows, in multiples of the widest block
+    pub delta_factor: f32,
+    // Blocks taken from the heap for each row pair, in multiples of the
+    // row's capacity (scaled up for a longer lookahead)
+    pub fill_factor: f32,
+    // Most nodes kept at each level, lowest cost first (None keeps
+    // every node that survives the length filter)
+    pub beam_width: std::option::Option<usize>,
+    // Lower row lengths within the same bucket of this size are
+    // treated as equal when filtering (0.0 for exact lengths)
+    pub beam_bucket: f32,
+    // Rows each block can be placed in: the lower row, plus rows
+    // further up that it can be deferred to (2 is the row pair)
+    pub lookahead: usize,
+    pub mode: HcwtMode,
+    // The standard-cell path works in multiples of the average cell
+    // width: slack over the row target for packing without solving,
+    // the largest row length difference, and the blocks taken past
+    // the fill factor times the row target
+    pub standard_hard_max_cells: f32,
+    pub standard_delta_cells: f32,
+    pub standard_extra_cells: f32,
+}
+
+impl Default for HcwtConfig {
+    fn default() -> Self {
+        HcwtConfig {
+            upper_weight: 0.8,
+            upper_horizontal_weight: 0.1,
+            top_horizontal_weight: 0.6,
+            hard_max_slack: 10.0,
+            last_row_slack: 1000.0,
+            delta_factor: 4.0,
+            fill_factor: 2.0,
+            beam_width: None,
+            beam_bucket: 0.0,
+            lookahead: 2,
+            mode: HcwtMode::Mixed,
+            standard_hard_max_cells: 10.0,
+            standard_delta_cells: 15.0,
+            standard_extra_cells: 5.0,
+        }
     }
-    Ordering::Equal
+}
+
+// Most rows a block can be deferred across in one solve
+pub const MAX_LOOKAHEAD: usize = 4;
+
+// Blocks to take for each solve, in multiples of the row target.  The
+// fill factor is for a row pair; every row in a longer window needs
+// its share too, or the lower row can never reach its target.
+fn candidate_factor(config: &HcwtConfig) -> f32 {
+    config.fill_factor * config.lookahead.clamp(2, MAX_LOOKAHEAD) as f32 / 2.0
 }
 
 #[derive(Clone)]
@@ -53,15 +135,28 @@ struct HcwtRowPair {
     pub x: f32,
     pub y0: f32,
     pub y1: f32,
+    pub lookahead: usize, // Rows in the window, 2 or more
+    pub row_step: f32,    // Spacing of the rows past y1
     pub length: f32,
     pub hard_max: f32,
     pub delta: f32,
     pub upper_weight: f32,
     pub upper_horizontal_weight: f32,
-    pub upper: Vec<LegalBlock>,
+    pub upper: Vec<LegalBlock>, // Everything deferred past the lower row
     pub lower: Vec<LegalBlock>,
 }
 
+impl HcwtRowPair {
+    // Target Y for each row in the window
+    fn row_y(&self, row: usize) -> f32 {
+        if row == 0 {
+            self.y0
+        } else {
+            self.y1 + (row - 1) as f32 * self.row_step
+        }
+    }
+}
+
 fn pack_row(rowpair: &mut HcwtRowPair) {
     // Right now, just put the first ones into the lower, then the rest in the upper
     let mut len0 = 0.0;
@@ -80,15 +175,57 @@ fn pack_row(rowpair: &mut HcwtRowPair) {
 
 #[derive(Clone, Copy)]
 struct Node {
-    upper: f32,
-    lower: f32,
+    fill: [f32; MAX_LOOKAHEAD], // Length used in each row, fill[0] is the lower row
     cost: f32,
-    decision: bool,
+    row: usize, // Which row the block went into
     block_index: usize,
 }
 
+// Counts from the HCwT solves, to see where the time goes
+#[derive(Copy, Clone, Debug, Default)]
+pub struct HcwtStats {
+    pub solves: usize,          // Row pairs that went through HCwT
+    pub quick_packs: usize,     // Row pairs that fit under hard_max
+    pub nodes_generated: usize, // Every child of every node
+    pub nodes_kept: usize,      // Survivors of all filtering
+    pub filtered_delta: usize,  // Row lengths too far apart
+    pub filtered_length: usize, // Same (bucketed) length, higher cost
+    pub filtered_beam: usize,   // Past the beam width
+}
+
+impl HcwtStats {
+    pub fn add(&mut self, other: &HcwtStats) {
+        self.solves += other.solves;
+        self.quick_packs += other.quick_packs;
+        self.nodes_generated += other.nodes_generated;
+        self.nodes_kept += other.nodes_kept;
+        self.filtered_delta += other.filtered_delta;
+        self.filtered_length += other.filtered_length;
+        self.filtered_beam += other.filtered_beam;
+    }
+}
+
+impl std::fmt::Display for HcwtStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} solves ({} quick packs)  {} nodes generated, {} kept  filtered: {} delta, {} length, {} beam",
+            self.solves,
+            self.quick_packs,
+            self.nodes_generated,
+            self.nodes_kept,
+            self.filtered_delta,
+            self.filtered_length,
+            self.filtered_beam
+        )
+    }
+}
+
 struct Context {
     rowpair: HcwtRowPair,
+    beam_width: std::option::Option<usize>,
+    beam_bucket: f32,
+    stats: HcwtStats,
 }
 
 struct Option {
@@ -98,7 +235,7 @@ struct Option {
 use hcwt_r::HCwT;
 use hcwt_r::SelectionNode;
 
-fn pack_row_hcwt(rowpair: &mut HcwtRowPair) {
+fn pack_row_hcwt(rowpair: &mut HcwtRowPair, config: &HcwtConfig, stats: &mut HcwtStats) {
     // Check to see if everything can be packed in the
     // lower row
     let mut len = 0.0;
@@ -111,31 +248,37 @@ fn pack_row_hcwt(rowpair: &mut HcwtRowPair) {
         }
         #[cfg(feature="ldbg")]
         println!("HARDMAX quick pack of {}", len);
+        stats.quick_packs += 1;
         return;
     }
 
     let root_opt = Option { index: 0, row: 0 };
     let root_opts = vec![root_opt];
     let root_node = Node {
-        upper: 0.0,
-        lower: 0.0,
+        fill: [0.0; MAX_LOOKAHEAD],
         cost: 0.0,
-        decision: true,
+        row: 0,
         block_index: 0,
     };
     let root_nodes = vec![root_node];
     let mut hcwt: HCwT<Context, Option, Node> = HCwT::new(root_opts, root_nodes);
     for i in 0..rowpair.blocks.len() {
         let mut dx = hcwt.new_decision();
-        hcwt.add_option(&mut dx, Option { index: i, row: 0 });
-        hcwt.add_option(&mut dx, Option { index: i, row: 1 });
+        for row in 0..rowpair.lookahead {
+            hcwt.add_option(&mut dx, Option { index: i, row });
+        }
         hcwt.add_decision(dx);
     }
     let mut share_context = Context {
         rowpair: rowpair.clone(),
+        beam_width: config.beam_width,
+        beam_bucket: config.beam_bucket,
+        stats: HcwtStats::default(),
     };
 
     hcwt.solve(&mut share_context, &generate, &filter);
+    share_context.stats.solves += 1;
+    stats.add(&share_context.stats);
     let last = &hcwt.levels.last().unwrap().nodes;
     let level = hcwt.levels.len() - 1;
     let mut best = 0;
@@ -145,7 +288,7 @@ fn pack_row_hcwt(rowpair: &mut HcwtRowPair) {
     for i in 0..last.len() {
         let node = &last[i];
         // println!("Final level solution {} {}  cost {}", node.detail.lower, node.detail.upper, node.detail.cost);
-        let delta = (rowpair.length - node.detail.lower).abs();
+        let delta = (rowpair.length - node.detail.fill[0]).abs();
         if delta < mismatch {
             best = i;
             mismatch = delta;
@@ -177,45 +320,68 @@ fn generate(
     let mut new_node = parent_node.detail;
     new_node.block_index = detail.index;
     let block = &context.rowpair.blocks[detail.index];
+    let row = detail.row;
     let dx;
     let dy;
-    if detail.row == 0 {
-        dx = new_node.lower - block.x;
+    if row == 0 {
+        dx = context.rowpair.x + new_node.fill[0] - block.x;
         dy = context.rowpair.y0 - block.y;
-        let delta_cost = (dx * dx + dy * dy) * block.w;
+        let delta_cost = (dx * dx + dy * dy) * block.w * block.weight;
 
-        new_node.lower += block.w;
-        new_node.decision = true;
         new_node.cost += delta_cost;
     } else {
-        dx = (new_node.upper - block.x) * context.rowpair.upper_horizontal_weight;
-        dy = context.rowpair.y1 - block.y;
-        let delta_cost = (dx * dx + dy * dy) * block.w;
-        new_node.upper += block.w;
-        new_node.decision = false;
+        dx = (context.rowpair.x + new_node.fill[row] - block.x)
+            * context.rowpair.upper_horizontal_weight;
+        dy = context.rowpair.row_y(row) - block.y;
+        let delta_cost = (dx * dx + dy * dy) * block.w * block.weight;
 
-        // Slightly less penalty for movement in the upper row
+        // Slightly less penalty for movement in the upper rows
         new_node.cost += delta_cost * context.rowpair.upper_weight;
         // new_node.cost += ((dx*dx*dx).abs() + (dy*dy*dy).abs()) * context.rowpair.upper_weight;
         // new_node.cost += (dx + dy).abs();
     }
+    new_node.fill[row] += block.w;
+    new_node.row = row;
 
-    // Check for difference in row lengths
-    let delta = new_node.upper - new_node.lower;
-    if delta.abs() <= context.rowpair.delta {
+    // Check for difference in row lengths.  Only neighbouring rows are
+    // held within delta of each other; the candidates are sized for two
+    // rows, so a window of three or four could not fill the lower row
+    // if every row had to keep up with it.
+    context.stats.nodes_generated += 1;
+    let window = &new_node.fill[0..context.rowpair.lookahead];
+    if window.windows(2).all(|pair| (pair[0] - pair[1]).abs() <= context.rowpair.delta) {
         nodes.push(SelectionNode {
             parent: parent_index,
             detail: new_node,
         });
+    } else {
+        context.stats.filtered_delta += 1;
     }
 }
 
-fn node_compare(a: &Node, b: &Node) -> Ordering {
-    if a.lower < b.lower {
-        return Ordering::Less;
+// Row length, rounded down to a bucket if there is one
+fn bucketed(length: f32, bucket: f32) -> f32 {
+    if bucket > 0.0 {
+        (length / bucket).floor()
+    } else {
+        length
     }
-    if a.lower > b.lower {
-        return Ordering::Greater;
+}
+
+// Order by the (bucketed) row lengths, lower row first, then cost, and
+// then the row the last block went into.  Nodes are generated in a
+// fixed order from the sorted blocks, and the sorts are stable, so any
+// ties left keep the node that was generated first.
+fn node_compare(a: &Node, b: &Node, bucket: f32) -> Ordering {
+    for row in 0..MAX_LOOKAHEAD {
+        let a_len = bucketed(a.fill[row], bucket);
+        let b_len = bucketed(b.fill[row], bucket);
+        if a_len < b_len {
+            return Ordering::Less;
+        }
+        if a_len > b_len {
+            return Ordering::Greater;
+        }
     }
     if a.cost < b.cost {
         return Ordering::Less;
@@ -223,11 +389,15 @@ fn node_compare(a: &Node, b: &Node) -> Ordering {
     if a.cost > b.cost {
         return Ordering::Greater;
     }
-    Ordering::Equal
+    a.row.cmp(&b.row)
+}
+
+fn same_lengths(a: &Node, b: &Node, bucket: f32) -> bool {
+    (0..MAX_LOOKAHEAD).all(|row| bucketed(a.fill[row], bucket) == bucketed(b.fill[row], bucket))
 }
 fn filter(
     _hcwt: &HCwT<Context, Option, Node>,
-    _context: &mut Context,
+    context: &mut Context,
     level: usize,
     nodes: &mut Vec<SelectionNode<Node>>,
 ) -> Vec<SelectionNode<Node>> {
@@ -237,23 +407,33 @@ fn filter(
         println!("NO NODES!");
         return result;
     }
-    nodes.sort_by(|a, b| node_compare(&a.detail, &b.detail));
-    let mut len = nodes[0].detail.lower;
-    let mut cost = nodes[0].detail.cost;
+    let bucket = context.beam_bucket;
+    nodes.sort_by(|a, b| node_compare(&a.detail, &b.detail, bucket));
+    let mut last = nodes[0].detail;
     result.push(nodes[0]);
     // println!("Node 0 length {}:{} cost {}", len, nodes[0].detail.upper, cost);
     for i in 1..nodes.len() {
-        // The lowest cost result for any given length is of interest
-        if nodes[i].detail.lower != len {
-            //  && cost > nodes[i].detail.cost {
+        // The lowest cost result for any given set of lengths is of interest
+        if !same_lengths(&nodes[i].detail, &last, bucket) {
             result.push(nodes[i]);
-            len = nodes[i].detail.lower;
-            cost = nodes[i].detail.cost;
+            last = nodes[i].detail;
             // println!("Save node {} length {}:{} cost {}", i, nodes[i].detail.lower, nodes[i].detail.upper, nodes[i].detail.cost);
         } else {
             // println!("   x node {} length {}:{} cost {}", i, nodes[i].detail.lower, nodes[i].detail.upper, nodes[i].detail.cost);
         }
     }
+    context.stats.filtered_length += nodes.len() - result.len();
+
+    // Beam: only the cheapest few lengths go on to the next level
+    if let Some(width) = context.beam_width {
+        if result.len() > width.max(1) {
+            result.sort_by(|a, b| a.detail.cost.partial_cmp(&b.detail.cost).unwrap());
+            context.stats.filtered_beam += result.len() - width.max(1);
+            result.truncate(width.max(1));
+        }
+    }
+    context.stats.nodes_kept += result.len();
+
     if result.len() == 0 {
         println!("Filtered {} nodes down to {}", nodes.len(), result.len());
     }
@@ -265,23 +445,23 @@ fn trace(
     hcwt: &HCwT<Context, Option, Node>,
     level: usize,
     index: usize,
-    upper: &mut Vec<usize>,
     lower: &mut Vec<usize>,
+    upper: &mut Vec<usize>,
 ) {
     if level > 0 {
         trace(
             hcwt,
             level - 1,
             hcwt.levels[level].nodes[index].parent,
-            upper,
             lower,
+            upper,
         );
         let nd = &hcwt.levels[level].nodes[index].detail;
-        // println!("{} {}  cost {} decision: {} block {}", nd.lower, nd.upper, nd.cost, nd.decision, nd.block_index);
-        if nd.decision {
-            upper.push(nd.block_index);
-        } else {
+        // println!("{:?}  cost {} row: {} block {}", nd.fill, nd.cost, nd.row, nd.block_index);
+        if nd.row == 0 {
             lower.push(nd.block_index);
+        } else {
+            upper.push(nd.block_index);
         }
     }
 }
@@ -309,27 +489,36 @@ struct Pool {
     pub blocks: Vec<LegalBlock>,
     pub start: f32, // X coordinates
     pub stop: f32,
+    pub limit: f32,  // Hard right edge, a macro or the core boundary
     pub y: f32,      // Y location for the lower row
     pub target: f32, // How much per row
     pub filled: f32, // How much has been put into the pool
 }
 
-fn make_pools(row: &Row, end_row: f32) -> Vec<Pool> {
+// The pools are the free stretches of a row, between blockages, up to
+// end_row.  The target is the whole free length; the caller scales it
+// down to the average utilization.  The last pool may run past end_row
+// as far as the core boundary if it overflows.
+fn make_pools(row: &Row, start_row: f32, end_row: f32, core_right: f32) -> Vec<Pool> {
     let mut pools = Vec::new();
-    let mut start = 0.0;
+    let mut start = start_row;
     for blockage in &row.blockages {
+        if start >= end_row {
+            break;
+        }
         if start < blockage.start {
             let target = blockage.start - start;
             pools.push(Pool {
                 blocks: Vec::new(),
                 start,
                 stop: blockage.start,
+                limit: blockage.start,
                 y: 0.0,
                 target,
                 filled: 0.0,
             });
         }
-        start = blockage.end;
+        start = f32::max(start, blockage.end);
     }
     // And potentially one more pool after the last blockage
     if start < end_row {
@@ -338,6 +527,7 @@ fn make_pools(row: &Row, end_row: f32) -> Vec<Pool> {
             blocks: Vec::new(),
             start,
             stop: end_row,
+            limit: core_right,
             y: 0.0,
             target,
             filled: 0.0,
@@ -363,110 +553,42 @@ fn pool_distance(pool: &Pool, location: f32) -> f32 {
     0.0
 }
 
-fn add_to_pool(pools: &mut Vec<Pool>, block: &LegalBlock) {
+// Add a block to the nearest pool that still has room for it (up to
+// the fill factor times its length), or the nearest pool if none do.
+fn add_to_pool(pools: &mut Vec<Pool>, block: &LegalBlock, fill_factor: f32) {
     if pools.len() == 1 {
         pools[0].filled += block.w;
         pools[0].blocks.push(*block);
         return;
     }
-    let mut best_pool = 0;
     let location = block.x + block.w / 2.0;
-    let mut best_dist = pool_distance(&pools[0], location);
-    for i in 1..pools.len() {
-        let d = pool_distance(&pools[i], location);
-        if d < best_dist {
-            best_pool = i;
-            best_dist = d;
+    let mut best: std::option::Option<(usize, f32)> = None;
+    let mut nearest = (0, f32::MAX);
+    for (i, pool) in pools.iter().enumerate() {
+        let d = pool_distance(pool, location);
+        if d < nearest.1 {
+            nearest = (i, d);
+        }
+        let fits = pool.filled + block.w <= (pool.limit - pool.start) * fill_factor;
+        if fits && best.is_none_or(|(_, best_dist)| d < best_dist) {
+            best = Some((i, d));
         }
     }
+    let (best_pool, _best_dist) = best.unwrap_or(nearest);
     #[cfg(feature = "ldbg")]
     println!(
         "Add block {} with width {} to pool {} distance {} target {}",
-        block.tag, block.w, best_pool, best_dist, pools[best_pool].target
+        block.tag, block.w, best_pool, _best_dist, pools[best_pool].target
     );
     pools[best_pool].filled += block.w;
     pools[best_pool].blocks.push(*block);
 }
 
-fn snap_macros(
+fn legalize_mixed(
     lp: &LegalProblem,
-    target: f32,
-    macros: &mut Vec<LegalBlock>,
-    legal_positions: &mut Vec<LegalPosition>,
-) {
-    let top = lp.params.origin_y + lp.params.grid_y as f32 * lp.params.step_y;
-    // let right = lp.params.grid_x as f32 * lp.params.step_x;
-    let right = target;
-    let deadband = 2.0 * lp.params.step_y;
-
-    for block in macros {
-        #[cfg(feature = "ldbg")]
-        println!("Snap block {}", block.tag);
-        let original_x = block.x;
-        let original_y = block.y;
-        let mut lblock = *block;
-        lblock.x = (block.x / lp.params.step_x).round() * lp.params.step_x;
-        lblock.y = (block.y / lp.params.step_y).round() * lp.params.step_y;
-
-        // Shift in from the sides
-        if lblock.x > right {
-            lblock.x = right - lblock.w;
-        }
-        if lblock.x < lp.params.origin_x {
-            lblock.x = lp.params.origin_x;
-        }
-
-        // Shift in from the top
-        if lblock.y + lblock.h > top {
-            lblock.y = top - lblock.h;
-        }
-        if lblock.y < lp.params.origin_y {
-            lblock.y = lp.params.origin_y;
-        }
-
-        // Snap to the boundaries if close
-        if right - (lblock.x + lblock.w) < deadband {
-            #[cfg(feature = "ldbg")]
-            println!("Snap block {} right because of deadband.", block.tag);
-            lblock.x = right - lblock.w;
-        }
-        if lblock.x < deadband {
-            #[cfg(feature = "ldbg")]
-            println!("Snap block {} left because of deadband.", block.tag);
-            lblock.x = 0.0;
-        }
-
-        if top - (lblock.y + lblock.h) < deadband {
-            #[cfg(feature = "ldbg")]
-            println!("Snap block {} up because of deadband.", block.tag);
-            lblock.y = top - lblock.h;
-        }
-        if lblock.y < deadband {
-            #[cfg(feature = "ldbg")]
-            println!("Snap block {} down because of deadband.", block.tag);
-            lblock.y = 0.0;
-        }
-
-        legal_positions.push(LegalPosition {
-            block_tag: block.tag,
-            x: lblock.x,
-            y: lblock.y,
-            h: block.h,
-            w: block.w,
-            original_x,
-            original_y,
-        });
-        #[cfg(feature = "ldbg")]
-        println!(
-            "Shift {} from {} {} to {} {}",
-            block.tag, block.x, block.y, lblock.x, lblock.y
-        );
-        block.x = lblock.x;
-        block.y = lblock.y;
-    }
-}
-
-fn legalize_mixed(lp: &LegalProblem) -> Vec<LegalPosition> {
+    config: &HcwtConfig,
+    stats: &mut HcwtStats,
+) -> Vec<LegalPosition> {
     #[cfg(feature = "ldbg")]
     println!("SPECIAL MIXED HCWT");
     let mut legal_positions = Vec::new();
@@ -477,6 +599,7 @@ fn legalize_mixed(lp: &LegalProblem) -> Vec<LegalPosition> {
     }
     //let target = (area / (lp.params.grid_y as f32 * lp.params.step_y)).round();
     let target = area / (lp.params.grid_y as f32 * lp.params.step_y);
+    let core_right = lp.params.origin_x + lp.params.grid_x as f32 * lp.params.step_x;
     #[cfg(feature = "ldbg")]
     println!("Target {:.1} in each row", target);
 
@@ -485,18 +608,25 @@ fn legalize_mixed(lp: &LegalProblem) -> Vec<LegalPosition> {
         legal_block_cmp_y(a, b).reverse()
     });
 
-    let mut macros = Vec::new();
+    // Legalize the macro blocks first, put cells into the heap
+    let (mut macros, cells) = macros::split(&lp.blocks, &lp.params);
     let mut pool_supply = 0.0;
-    // Find the macro blocks, put cells into the heap
-    for block in &lp.blocks {
-        if block.h > lp.params.step_y {
-            macros.push(*block);
-        } else {
-            bhp.push(*block);
-            pool_supply += block.w;
-        }
+    // Pools only extend as far as the cells do, or far enough to hold them
+    let cell_right = cells
+        .iter()
+        .map(|b| b.x + b.w)
+        .fold(lp.params.origin_x + target, f32::max)
+        .min(core_right);
+    for block in cells {
+        bhp.push(block);
+        pool_supply += block.w;
+    }
+    let macro_positions = macros::legalize_macros(&lp.params, &macros);
+    for (block, position) in macros.iter_mut().zip(macro_positions) {
+        block.x = position.x;
+        block.y = position.y;
+        legal_positions.push(position);
     }
-    snap_macros(lp, target, &mut macros, &mut legal_positions);
 
     let mut rows = Vec::new();
     // Now figure out the target amount in each row
@@ -508,8 +638,8 @@ fn legalize_mixed(lp: &LegalProblem) -> Vec<LegalPosition> {
         rows.push(newrow);
     }
     for mb in &macros {
-        let lowrow = (mb.y / lp.params.step_y) as usize;
-        let mut highrow = ((mb.y + mb.h) / lp.params.step_y) as usize;
+        let lowrow = ((mb.y - lp.params.origin_y) / lp.params.step_y).max(0.0) as usize;
+        let mut highrow = ((mb.y + mb.h - lp.params.origin_y) / lp.params.step_y - 0.001).ceil() as usize;
         if highrow >= rows.len() {
             highrow = rows.len();
         }
@@ -523,10 +653,33 @@ fn legalize_mixed(lp: &LegalProblem) -> Vec<LegalPosition> {
             println!("Block {} uses row {}, consumes {}", mb.tag, row, mb.w);
         }
     }
+    // Each pool is filled to the same fraction of its free length: what
+    // is left of the cells, spread over what is left of the free space
+    let mut free = Vec::new();
+    for row in &mut rows {
+        row.blockages.sort_by(|a, b| block_compare(&a, &b));
+        let pools = make_pools(row, lp.params.origin_x, cell_right, core_right);
+        free.push(pools.iter().map(|p| p.target).sum::<f32>());
+    }
+    let mut free_remaining: f32 = free.iter().sum();
+
     for row in 0..lp.params.grid_y {
-        rows[row].blockages.sort_by(|a, b| block_compare(&a, &b));
+        let utilization = if free_remaining > 0.0 {
+            (pool_supply / free_remaining).min(1.0)
+        } else {
+            1.0
+        };
+        free_remaining -= free[row];
+
         // Fill the pools
-        let mut pools = make_pools(&rows[row], target);
+        let mut pools = make_pools(&rows[row], lp.params.origin_x, cell_right, core_right);
+        if pools.is_empty() {
+            continue;
+        }
+        for p in &mut pools {
+            p.target *= utilization;
+        }
+        let last_row = row + 1 == lp.params.grid_y;
         let mut fill = 0.0;
         let mut widest = 0.0;
         for p in &pools {
@@ -534,7 +687,11 @@ fn legalize_mixed(lp: &LegalProblem) -> Vec<LegalPosition> {
         }
         #[cfg(feature = "ldbg")]
         println!("Row {} target fill {} pool has {}", row, fill, pool_supply);
-        fill = fill * 2.0;
+        fill = fill * candidate_factor(config);
+        if last_row {
+            // Nothing can be left in the heap after the last row
+            fill = f32::MAX;
+        }
         let mut taken = 0.0;
 
         // Now fill up the pools
@@ -544,12 +701,17 @@ fn legalize_mixed(lp: &LegalProblem) -> Vec<LegalPosition> {
             if block.w > widest {
                 widest = block.w;
             }
-            add_to_pool(&mut pools, &block);
+            add_to_pool(&mut pools, &block, candidate_factor(config));
         }
         pool_supply -= taken;
 
-        // Now run HCwT for each pool
-        for p in &pools {
+        // Now run HCwT for each pool.  Whatever a pool cannot hold goes
+        // back into the heap for the next row -- or, in the last row,
+        // on to the next pool over.
+        let mut spill = Vec::new();
+        let num_pools = pools.len();
+        for (i, p) in pools.iter_mut().enumerate() {
+            p.blocks.append(&mut spill);
             #[cfg(feature = "ldbg")]
             println!("POOL {} to {} target {} fill {} pool_supply {}", p.start, p.stop, p.target, p.filled, pool_supply);
             let mut rowpair = HcwtRowPair {
@@ -557,37 +719,51 @@ fn legalize_mixed(lp: &LegalProblem) -> Vec<LegalPosition> {
                 x: p.start,
                 y0: lp.params.origin_y + row as f32 * lp.params.step_y,
                 y1: lp.params.origin_y + (row + 2) as f32 * lp.params.step_y,
+                lookahead: config.lookahead.clamp(2, MAX_LOOKAHEAD),
+                row_step: lp.params.step_y,
                 length: p.target,
-                hard_max: p.target + 10.0,
-                delta: widest * 4.0,
-                upper_weight: 0.8,
-                upper_horizontal_weight: 0.1,
+                hard_max: p.target + config.hard_max_slack,
+                delta: widest * config.delta_factor,
+                upper_weight: config.upper_weight,
+                upper_horizontal_weight: config.upper_horizontal_weight,
                 upper: Vec::new(),
                 lower: Vec::new(),
             };
-            if row == lp.params.grid_y - 1 {
-                rowpair.hard_max += 1000.0;
+            if last_row {
+                rowpair.hard_max += config.last_row_slack;
             }
             rowpair.blocks.sort_by(|a, b| legal_block_cmp_x(a, b));
-            pack_row_hcwt(&mut rowpair);
-            let mut x = p.start;
-            let mut taken = 0.0;
-            for block in rowpair.lower {
-                // println!("  Block {} to {}", block.tag, x);
-                legal_positions.push(LegalPosition {
-                    block_tag: block.tag,
-                    x,
-                    y: rowpair.y0,
-                    h: block.h,
-                    w: block.w,
-                    original_x: block.x,
-                    original_y: block.y,
-                });
-                x += block.w;
-                taken += block.w;
-                // row_taken += block.w;
-                // total_taken += block.w;
+            pack_row_hcwt(&mut rowpair, config, stats);
+
+            let mut lower = rowpair.lower;
+            if last_row {
+                // There is no row above the last one
+                lower.append(&mut rowpair.upper);
+                lower.sort_by(|a, b| legal_block_cmp_x(a, b));
             }
+            let capacity = p.limit - p.start;
+            let mut taken: f32 = lower.iter().map(|b| b.w).sum();
+            if taken > capacity {
+                if !last_row {
+                    // The blocks that wanted to be highest go up a row
+                    lower.sort_by(|a, b| legal_block_cmp_y(a, b));
+                    while taken > capacity && !lower.is_empty() {
+                        let block = lower.pop().unwrap();
+                        taken -= block.w;
+                        rowpair.upper.push(block);
+                    }
+                    lower.sort_by(|a, b| legal_block_cmp_x(a, b));
+                } else if i + 1 < num_pools {
+                    // The rightmost blocks move over to the next pool
+                    while taken > capacity && !lower.is_empty() {
+                        let block = lower.pop().unwrap();
+                        taken -= block.w;
+                        spill.push(block);
+                    }
+                }
+            }
+
+            placerow::emit_row(&lower, p.start, p.limit, rowpair.y0, &mut legal_positions);
             #[cfg(feature = "ldbg")]
             println!("Row takes {}, target was {}", taken, p.target);
             for block in rowpair.upper {
@@ -606,24 +782,114 @@ fn legalize_mixed(lp: &LegalProblem) -> Vec<LegalPosition> {
         );
         println!("Heap has {} entries\n\n", bhp.len());
     }
-    // It's possible that a pool exceeded capacity, and overlaps a
-    // fixed macro block...  The compress step will take care of that
+    // Anything still in the heap never found a pool (the top rows are
+    // blocked); put it past the right edge of the top row
+    let mut x = core_right;
+    while let Some(block) = bhp.pop() {
+        legal_positions.push(LegalPosition {
+            block_tag: block.tag,
+            x,
+            y: lp.params.origin_y + (lp.params.grid_y.max(1) - 1) as f32 * lp.params.step_y,
+            h: block.h,
+            w: block.w,
+            original_x: block.x,
+            original_y: block.y,
+        });
+        x += block.w;
+    }
+
+    // Pools only overflow in the last row, if at all.  Only if
+    // something overlaps does the whole thing get compacted.
+    let violations = super::count_violations(&legal_positions, &lp.params);
+    if violations == 0 {
+        return legal_positions;
+    }
+    #[cfg(feature = "ldbg")]
+    println!("{} violations after HCwT, compacting", violations);
     let compressed = lp.new_from(&legal_positions);
-    super::tetris::legalize_floorplan(&compressed)
+    let mut compacted = super::tetris::legalize_floorplan(&compressed);
+
+    // Compaction only knows where the blocks were legalized to, not
+    // where they started out
+    let mut original = std::collections::HashMap::new();
+    for block in &lp.blocks {
+        original.insert(block.tag, (block.x, block.y));
+    }
+    for pos in &mut compacted {
+        if let Some(&(x, y)) = original.get(&pos.block_tag) {
+            pos.original_x = x;
+            pos.original_y = y;
+        }
+    }
+    compacted
     // legal_positions
 }
 pub fn legalize(lp: &LegalProblem) -> Vec<LegalPosition> {
+    legalize_config(lp, &HcwtConfig::default())
+}
+
+pub fn legalize_config(lp: &LegalProblem, config: &HcwtConfig) -> Vec<LegalPosition> {
+    legalize_stats(lp, config).0
+}
+
+// Legalize, and also return the counts from the HCwT solves
+pub fn legalize_stats(lp: &LegalProblem, config: &HcwtConfig) -> (Vec<LegalPosition>, HcwtStats) {
+    let mut stats = HcwtStats::default();
+    let legal_positions = legalize_standard_or_mixed(lp, config, &mut stats);
+    (legal_positions, stats)
+}
+
+fn legalize_standard_or_mixed(
+    lp: &LegalProblem,
+    config: &HcwtConfig,
+    stats: &mut HcwtStats,
+) -> Vec<LegalPosition> {
     #[cfg(feature = "ldbg")]
     println!("HCWT placement legalizer");
     // See if we have any macro blocks -- if so, we need to use the mixed legalizer
     for block in &lp.blocks {
         if block.h > lp.params.step_y {
-            return legalize_mixed(lp);
+            if config.mode != HcwtMode::Mixed {
+                println!("Macro blocks present, using the mixed HCwT legalizer");
+            }
+            return legalize_mixed(lp, config, stats);
+        }
+    }
+
+    match config.mode {
+        HcwtMode::Mixed => legalize_mixed(lp, config, stats),
+        HcwtMode::Standard => legalize_standard(lp, config, stats),
+        HcwtMode::Best => {
+            // Only the stats of the run that is kept are reported
+            let mut mixed_stats = HcwtStats::default();
+            let mut standard_stats = HcwtStats::default();
+            let mixed = legalize_mixed(lp, config, &mut mixed_stats);
+            let standard = legalize_standard(lp, config, &mut standard_stats);
+            let mixed_displace = Metrics::new(&mixed).total;
+            let standard_displace = Metrics::new(&standard).total;
+            #[cfg(feature = "ldbg")]
+            println!(
+                "Mixed displacement {} standard {}",
+                mixed_displace, standard_displace
+            );
+            if standard_displace < mixed_displace {
+                stats.add(&standard_stats);
+                standard
+            } else {
+                stats.add(&mixed_stats);
+                mixed
+            }
         }
     }
-    println!("STANDARD CELL ONLY but we'll use the mixed legalize anyway");
-    return legalize_mixed(lp);
+}
 
+// Standard-cell only HCwT: ferry-load whole rows from the heap, and
+// after each row, spread what is left over the remaining rows.
+fn legalize_standard(
+    lp: &LegalProblem,
+    config: &HcwtConfig,
+    stats: &mut HcwtStats,
+) -> Vec<LegalPosition> {
     let mut legal_positions = Vec::new();
 
     // Total length of all cells
@@ -642,6 +908,7 @@ pub fn legalize(lp: &LegalProblem) -> Vec<LegalPosition> {
 
     let target = total_length / lp.params.grid_y as f32;
     let avg_cell = total_length / lp.blocks.len() as f32;
+    let core_right = lp.params.origin_x + lp.params.grid_x as f32 * lp.params.step_x;
 
     #[cfg(feature = "ldbg")]
     println!(
@@ -650,6 +917,7 @@ pub fn legalize(lp: &LegalProblem) -> Vec<LegalPosition> {
         total_length / lp.params.grid_y as f32,
         total_length / lp.blocks.len() as f32
     );
+    #[cfg(feature = "ldbg")]
     println!("{} rows", lp.params.grid_y);
 
     let mut row_num = 0;
@@ -660,16 +928,22 @@ pub fn legalize(lp: &LegalProblem) -> Vec<LegalPosition> {
             x: lp.params.origin_x,
             y0: lp.params.origin_y + row_num as f32 * lp.params.step_y,
             y1: lp.params.origin_y + (row_num + 2) as f32 * lp.params.step_y,
+            lookahead: config.lookahead.clamp(2, MAX_LOOKAHEAD),
+            row_step: lp.params.step_y,
             length: row_target,
-            hard_max: row_target + avg_cell * 10.0,
-            delta: avg_cell * 15.0,
-            upper_weight: 0.8,
-            upper_horizontal_weight: 0.1,
+            hard_max: row_target + avg_cell * config.standard_hard_max_cells,
+            delta: avg_cell * config.standard_delta_cells,
+            upper_weight: config.upper_weight,
+            upper_horizontal_weight: config.upper_horizontal_weight,
             upper: Vec::new(),
             lower: Vec::new(),
         };
+        // The last row takes whatever is left
+        let last_row = row_num + 1 >= lp.params.grid_y;
         let mut taken = 0.0;
-        while taken < (target * 2.0 + avg_cell * 5.0) && !bhp.is_empty() {
+        while (last_row || taken < (target * candidate_factor(config) + avg_cell * config.standard_extra_cells))
+            && !bhp.is_empty()
+        {
             let block = bhp.pop().unwrap();
             taken += block.w;
             rowpair.blocks.push(block);
@@ -678,28 +952,20 @@ pub fn legalize(lp: &LegalProblem) -> Vec<LegalPosition> {
 
         // If we're near the last row, put the horizontal displacement up, because we're not
         // going to be able to recover
-        if row_num >= lp.params.grid_y - 2 {
-            rowpair.upper_horizontal_weight = 0.6;
-        }
-        pack_row_hcwt(&mut rowpair);
-
-        // Lower row gets packed, upper row goes back into the hopper
-        let mut x = lp.params.origin_x;
-        let mut row_taken = 0.0;
-        for block in rowpair.lower {
-            legal_positions.push(LegalPosition {
-                block_tag: block.tag,
-                x,
-                y: rowpair.y0,
-                h: block.h,
-                w: block.w,
-                original_x: block.x,
-                original_y: block.y,
-            });
-            x += block.w;
-            row_taken += block.w;
-            total_taken += block.w;
+        if row_num + 2 >= lp.params.grid_y {
+            rowpair.upper_horizontal_weight = config.top_horizontal_weight;
         }
+        if last_row {
+            rowpair.hard_max = f32::MAX;
+        }
+        pack_row_hcwt(&mut rowpair, config, stats);
+
+        // Lower row gets placed, upper row goes back into the hopper
+        let mut lower = rowpair.lower;
+        lower.sort_by(|a, b| legal_block_cmp_x(a, b));
+        placerow::emit_row(&lower, lp.params.origin_x, core_right, rowpair.y0, &mut legal_positions);
+        let row_taken: f32 = lower.iter().map(|b| b.w).sum();
+        total_taken += row_taken;
         // println!("Row {} take {}", row_num, row_taken);
         for block in rowpair.upper {
             bhp.push(block);
@@ -710,3 +976,71 @@ pub fn legalize(lp: &LegalProblem) -> Vec<LegalPosition> {
 
     legal_positions
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::legalize::random::Rng;
+
+    #[test]
+    fn lookahead_keeps_rows_filled() {
+        let mut rng = Rng::new(33);
+        let params = LegalParams {
+            grid_x: 60,
+            grid_y: 8,
+            origin_x: 0.0,
+            origin_y: 0.0,
+            step_x: 1.0,
+            step_y: 10.0,
+            alpha_right: 2.0,
+            alpha_left: 0.5,
+        };
+        let mut lp = LegalProblem {
+            blocks: Vec::new(),
+            params,
+        };
+        let mut area = 0.0;
+        while area < 0.8 * 60.0 * 8.0 {
+            let w = (2 + rng.below(7)) as f32;
+            lp.blocks.push(LegalBlock {
+                tag: lp.blocks.len(),
+                x: rng.range(0.0, 60.0 - w),
+                y: rng.range(0.0, 70.0),
+                h: 10.0,
+                w,
+                weight: 1.0,
+                priority: 0,
+            });
+            area += w;
+        }
+        let target = area / params.grid_y as f32;
+        let widest = lp.blocks.iter().map(|b| b.w).fold(0.0, f32::max);
+
+        for lookahead in 2..=MAX_LOOKAHEAD {
+            let config = HcwtConfig {
+                lookahead,
+                beam_width: Some(500),
+                ..HcwtConfig::default()
+            };
+            let delta = widest * config.delta_factor;
+            let legal = legalize_config(&lp, &config);
+            assert_eq!(legal.len(), lp.blocks.len());
+            assert_eq!(super::super::count_violations(&legal, &params), 0, "lookahead {}", lookahead);
+            let mut fill = vec![0.0; params.grid_y];
+            for pos in &legal {
+                fill[(pos.y / params.step_y).round() as usize] += pos.w;
+            }
+            // The last row takes whatever is left
+            for (row, f) in fill[..params.grid_y - 1].iter().enumerate() {
+                assert!(
+                    (f - target).abs() <= delta,
+                    "lookahead {} row {} fill {} target {}",
+                    lookahead,
+                    row,
+                    f,
+                    target
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,97 @@
+// Legalization with a limit on how far any one block can move.  The
+// Tetris cost model already steers blocks away from spots past the
+// limit (see `DisplacementLimit`), but a block that comes late in the
+// order can find everything near it taken, and the other legalizers
+// do not know about the limit at all.  So after each pass, the blocks
+// that went past the limit are given priority: they are legalized
+// first, on their own, and then fixed while the legalizer (any of
+// them) places everything else around them.  Blocks that are still
+// over the limit join them for the next round.  The round with the fewest
+// blocks over the limit (and then the least displacement) is kept.
+
+use super::fixed;
+use super::tetris::{self, TetrisConfig};
+use super::{LegalPosition, LegalProblem, Metrics};
+use std::collections::HashSet;
+
+// Blocks that moved further than max, as (tag, displacement), worst
+// first
+pub fn over_limit(legalization: &Vec<LegalPosition>, max: f32) -> Vec<(usize, f32)> {
+    let mut over: Vec<(usize, f32)> = legalization
+        .iter()
+        .map(|pos| {
+            let d = (pos.x - pos.original_x).abs() + (pos.y - pos.original_y).abs();
+            (pos.block_tag, d)
+        })
+        .filter(|(_, d)| *d > max)
+        .collect();
+    over.sort_by(|a, b| {
+        b.1.partial_cmp(&a.1)
+            .expect("Could not compare")
+            .then(a.0.cmp(&b.0))
+    });
+    over
+}
+
+// Legalize with the legalizer given, then re-legalize the blocks over
+// the limit in the config first, for up to the number of rounds given.
+// Blocks over the limit go into the nearest gaps (gap-aware Tetris,
+// which steers them within the limit), and are then fixed while the
+// legalizer places the rest around them.  The legalizer is given the
+// fixed positions; anything it leaves in their way is repaired.
+pub fn legalize<F>(
+    lp: &LegalProblem,
+    fixed: &Vec<LegalPosition>,
+    config: &TetrisConfig,
+    rounds: usize,
+    legalizer: &F,
+) -> Vec<LegalPosition>
+where
+    F: Fn(&LegalProblem, &Vec<LegalPosition>) -> Vec<LegalPosition>,
+{
+    let mut best = fixed::repair(lp, legalizer(lp, fixed), fixed, config);
+    let max = match config.max_displacement {
+        Some(max) => max,
+        None => return best,
+    };
+    let mut over = over_limit(&best, max);
+    let mut best_score = (over.len(), Metrics::new(&best).total);
+    let mut priority = HashSet::new();
+    for _round in 0..rounds {
+        if over.is_empty() {
+            break;
+        }
+        priority.extend(over.iter().map(|(tag, _)| *tag));
+        let (first, rest): (Vec<_>, Vec<_>) = lp.blocks.iter().partition(|b| priority.contains(&b.tag));
+        let first = LegalProblem {
+            blocks: first,
+            params: lp.params,
+        };
+        let rest = LegalProblem {
+            blocks: rest,
+            params: lp.params,
+        };
+        let mut result = tetris::legalize_gaps(&first, config, fixed);
+        let mut held = result.clone();
+        held.extend(fixed.iter().copied());
+        let placed = fixed::repair(&rest, legalizer(&rest, &held), &held, config);
+        result.extend(placed);
+
+        over = over_limit(&result, max);
+        let score = (over.len(), Metrics::new(&result).total);
+        #[cfg(feature = "ldbg")]
+        println!(
+            "Limit round {}: {} with priority, {} over {:.1}, displacement {:.1}",
+            _round,
+            priority.len(),
+            score.0,
+            max,
+            score.1
+        );
+        if score.0 < best_score.0 || (score.0 == best_score.0 && score.1 < best_score.1) {
+            best_score = score;
+            best = result;
+        }
+    }
+    best
+}
@@ -0,0 +1,330 @@
+// Macro legalization.  Macros (anything taller than a row) are
+// snapped to rows and sites, kept inside the core, and pulled apart
+// where they overlap.  The result is then treated as fixed by the
+// standard-cell legalizers.
+//
+// Overlap is removed with a pair of constraint graphs.  Each pair of
+// macros that overlaps is separated either horizontally or vertically,
+// whichever needs less movement, keeping their current order in that
+// direction.  The x and y positions are then solved independently, as
+// close as possible to the snapped originals.  Moving macros apart can
+// create new overlaps, so this repeats, keeping the constraints from
+// earlier rounds, until nothing overlaps.
+
+use super::compact;
+use super::freespace::FreeSpace;
+use super::{LegalBlock, LegalParams, LegalPosition};
+use std::collections::HashMap;
+
+// Overlaps smaller than this are rounding noise
+const EPSILON: f32 = 1.0e-3;
+
+// Rounds of constraint generation before giving up
+const MAX_ROUNDS: usize = 50;
+
+// Relaxation sweeps before the constraints are enforced exactly
+const RELAX_SWEEPS: usize = 50;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Axis {
+    Horizontal,
+    Vertical,
+}
+
+// Anything taller than a row is treated as a macro
+pub fn is_macro(block: &LegalBlock, params: &LegalParams) -> bool {
+    block.h > params.step_y
+}
+
+// Split the blocks into macros and standard cells
+pub fn split(blocks: &Vec<LegalBlock>, params: &LegalParams) -> (Vec<LegalBlock>, Vec<LegalBlock>) {
+    blocks.iter().partition(|b| is_macro(b, params))
+}
+
+// One direction of the problem: positions are origin + k * step (or
+// anywhere, if step is zero), and must lie within [origin, limit - size].
+struct AxisSpec {
+    origin: f32,
+    step: f32,
+    limit: f32,
+}
+
+impl AxisSpec {
+    fn snap(&self, p: f32) -> f32 {
+        if self.step <= 0.0 {
+            return p;
+        }
+        self.origin + ((p - self.origin) / self.step).round() * self.step
+    }
+
+    fn snap_up(&self, p: f32) -> f32 {
+        if self.step <= 0.0 {
+            return p;
+        }
+        self.origin + ((p - self.origin) / self.step - EPSILON).ceil() * self.step
+    }
+
+    fn snap_down(&self, p: f32) -> f32 {
+        if self.step <= 0.0 {
+            return p;
+        }
+        self.origin + ((p - self.origin) / self.step + EPSILON).floor() * self.step
+    }
+
+    // Nearest legal position within the core, or the origin if the
+    // block is larger than the core
+    fn clamp(&self, p: f32, size: f32) -> f32 {
+        let high = self.snap_down(self.limit - size);
+        self.snap(p).min(high).max(self.origin)
+    }
+}
+
+fn overlap(a0: f32, a1: f32, b0: f32, b1: f32) -> f32 {
+    a1.min(b1) - a0.max(b0)
+}
+
+// Solve one direction: positions as close to the targets as possible,
+// with p[b] >= p[a] + size[a] for every constraint (a, b).  Overlaps
+// are first shared out between the two blocks, larger blocks moving
+// less; the constraints are then enforced exactly with a forward pass
+// (pushing up), a backward pass (pulling back inside the core), and a
+// final forward pass in case the chain is too long to fit.
+fn solve_axis(
+    spec: &AxisSpec,
+    targets: &Vec<f32>,
+    sizes: &Vec<f32>,
+    weights: &Vec<f32>,
+    constraints: &Vec<(usize, usize)>,
+) -> Vec<f32> {
+    let n = targets.len();
+    let mut p: Vec<f32> = (0..n).map(|i| spec.clamp(targets[i], sizes[i])).collect();
+
+    for _ in 0..RELAX_SWEEPS {
+        let mut moved = false;
+        for &(a, b) in constraints {
+            let violation = p[a] + sizes[a] - p[b];
+            if violation > EPSILON {
+                let share = weights[b] / (weights[a] + weights[b]);
+                p[a] -= violation * share;
+                p[b] += violation * (1.0 - share);
+                moved = true;
+            }
+        }
+        for i in 0..n {
+            p[i] = p[i].max(spec.origin).min(spec.limit - sizes[i]);
+        }
+        if !moved {
+            break;
+        }
+    }
+
+    let order = compact::topological(n, constraints);
+    let mut preds = vec![Vec::new(); n];
+    let mut succs = vec![Vec::new(); n];
+    for &(a, b) in constraints {
+        preds[b].push(a);
+        succs[a].push(b);
+    }
+    let forward = |p: &mut Vec<f32>| {
+        for &i in &order {
+            let low = preds[i]
+                .iter()
+                .map(|&a| p[a] + sizes[a])
+                .fold(spec.origin, f32::max);
+            p[i] = spec.snap_up(p[i].max(low));
+        }
+    };
+    forward(&mut p);
+    for &i in order.iter().rev() {
+        let high = succs[i]
+            .iter()
+            .map(|&b| p[b])
+            .fold(spec.limit, f32::min);
+        p[i] = spec.snap_down(p[i].min(high - sizes[i]));
+    }
+    forward(&mut p);
+    p
+}
+
+// Legalize the macros, aligned to rows and sites.  The positions come
+// back in the same order as the blocks.
+pub fn legalize_macros(params: &LegalParams, macros: &Vec<LegalBlock>) -> Vec<LegalPosition> {
+    separate(params, macros, true)
+}
+
+// Pull overlapping blocks apart, horizontally or vertically, with the
+// least displacement, keeping them inside the core.  With align, the
+// blocks are also snapped to rows and sites; otherwise they can end up
+// anywhere (except for the few that drop into gaps at the end, which
+// land on rows).
+pub fn separate(params: &LegalParams, macros: &Vec<LegalBlock>, align: bool) -> Vec<LegalPosition> {
+    // Work in tag order, so that the result does not depend on the
+    // order the blocks were given in, and then put them back
+    let mut order: Vec<usize> = (0..macros.len()).collect();
+    order.sort_by_key(|&i| macros[i].tag);
+    let sorted: Vec<LegalBlock> = order.iter().map(|&i| macros[i]).collect();
+    let mut legal_positions = vec![None; macros.len()];
+    for (&i, pos) in order.iter().zip(separate_sorted(params, &sorted, align)) {
+        legal_positions[i] = Some(pos);
+    }
+    legal_positions.into_iter().flatten().collect()
+}
+
+fn separate_sorted(params: &LegalParams, macros: &Vec<LegalBlock>, align: bool) -> Vec<LegalPosition> {
+    let n = macros.len();
+    let xspec = AxisSpec {
+        origin: params.origin_x,
+        step: if align { params.step_x } else { 0.0 },
+        limit: params.origin_x + params.grid_x as f32 * params.step_x,
+    };
+    let yspec = AxisSpec {
+        origin: params.origin_y,
+        step: if align { params.step_y } else { 0.0 },
+        limit: params.origin_y + params.grid_y as f32 * params.step_y,
+    };
+
+    let tx: Vec<f32> = macros.iter().map(|b| xspec.clamp(b.x, b.w)).collect();
+    let ty: Vec<f32> = macros.iter().map(|b| yspec.clamp(b.y, b.h)).collect();
+    let widths: Vec<f32> = macros.iter().map(|b| b.w).collect();
+    let heights: Vec<f32> = macros.iter().map(|b| b.h).collect();
+    let weights: Vec<f32> = macros.iter().map(|b| (b.w * b.h).max(f32::MIN_POSITIVE)).collect();
+
+    let mut x = tx.clone();
+    let mut y = ty.clone();
+    let mut decided: HashMap<(usize, usize), Axis> = HashMap::new();
+    let mut horizontal = Vec::new();
+    let mut vertical = Vec::new();
+
+    for _round in 0..MAX_ROUNDS {
+        let mut overlaps = 0;
+        for i in 0..n {
+            for j in (i + 1)..n {
+                if decided.contains_key(&(i, j)) {
+                    continue;
+                }
+                let ox = overlap(x[i], x[i] + widths[i], x[j], x[j] + widths[j]);
+                let oy = overlap(y[i], y[i] + heights[i], y[j], y[j] + heights[j]);
+                if ox <= EPSILON || oy <= EPSILON {
+                    continue;
+                }
+                overlaps += 1;
+                // Aligned vertical moves are in whole rows
+                let ymove = yspec.snap_up(yspec.origin + oy) - yspec.origin;
+                let axis = if ox <= ymove {
+                    Axis::Horizontal
+                } else {
+                    Axis::Vertical
+                };
+                let (p, s) = match axis {
+                    Axis::Horizontal => (&x, &widths),
+                    Axis::Vertical => (&y, &heights),
+                };
+                // Ties go by tag, not by the order of the blocks
+                let (ci, cj) = (p[i] + s[i] / 2.0, p[j] + s[j] / 2.0);
+                let before = ci < cj || (ci == cj && macros[i].tag < macros[j].tag);
+                let pair = if before { (i, j) } else { (j, i) };
+                match axis {
+                    Axis::Horizontal => horizontal.push(pair),
+                    Axis::Vertical => vertical.push(pair),
+                }
+                decided.insert((i, j), axis);
+            }
+        }
+        #[cfg(feature = "ldbg")]
+        println!("Macro round {}: {} new overlaps", _round, overlaps);
+        if overlaps == 0 {
+            break;
+        }
+        x = solve_axis(&xspec, &tx, &widths, &weights, &horizontal);
+        y = solve_axis(&yspec, &ty, &heights, &weights, &vertical);
+    }
+
+    // Chains that are too long for the core end up sticking out.  Any
+    // macro that is still out of place drops into the nearest gap left
+    // by the others, largest first.
+    let top = yspec.limit;
+    let misplaced = |i: usize, x: &Vec<f32>, y: &Vec<f32>| {
+        x[i] < xspec.origin - EPSILON
+            || x[i] + widths[i] > xspec.limit + EPSILON
+            || y[i] < yspec.origin - EPSILON
+            || y[i] + heights[i] > top + EPSILON
+            || (0..n).any(|j| {
+                j != i
+                    && overlap(x[i], x[i] + widths[i], x[j], x[j] + widths[j]) > EPSILON
+                    && overlap(y[i], y[i] + heights[i], y[j], y[j] + heights[j]) > EPSILON
+            })
+    };
+    let mut bad = Vec::new();
+    for i in 0..n {
+        if misplaced(i, &x, &y) {
+            bad.push(i);
+        }
+    }
+    if !bad.is_empty() {
+        let mut space = FreeSpace::new(params);
+        for i in 0..n {
+            if !bad.contains(&i) {
+                space.carve_rect(x[i], y[i], widths[i], heights[i]);
+            }
+        }
+        bad.sort_by(|&a, &b| {
+            weights[b]
+                .partial_cmp(&weights[a])
+                .expect("Could not compare")
+                .then(macros[a].tag.cmp(&macros[b].tag))
+        });
+        for i in bad {
+            let block_rows = (heights[i] / params.step_y - EPSILON).ceil() as usize;
+            if block_rows > params.grid_y {
+                continue;
+            }
+            let mut best = None;
+            let mut best_cost = f32::MAX;
+            for row in 0..=(params.grid_y - block_rows) {
+                let rows = row..row + block_rows;
+                let ry = yspec.origin + row as f32 * params.step_y;
+                if let Some(fit) = space.nearest_fit(rows.clone(), tx[i], widths[i]) {
+                    // Keep to the sites if that still fits
+                    let fit = [xspec.snap_down(fit), xspec.snap_up(fit)]
+                        .into_iter()
+                        .find(|&s| space.nearest_fit(rows.clone(), s, widths[i]) == Some(s))
+                        .unwrap_or(fit);
+                    let cost = (fit - tx[i]).abs() + (ry - ty[i]).abs();
+                    if cost < best_cost {
+                        best_cost = cost;
+                        best = Some((fit, ry, rows));
+                    }
+                }
+            }
+            if let Some((bx, by, rows)) = best {
+                x[i] = bx;
+                y[i] = by;
+                space.carve(rows, bx, widths[i]);
+            }
+        }
+    }
+
+    let mut legal_positions = Vec::with_capacity(n);
+    for (i, block) in macros.iter().enumerate() {
+        #[cfg(feature = "ldbg")]
+        println!(
+            "Macro {} from {} {} to {} {}",
+            block.tag, block.x, block.y, x[i], y[i]
+        );
+        legal_positions.push(LegalPosition {
+            block_tag: block.tag,
+            x: x[i],
+            y: y[i],
+            h: block.h,
+            w: block.w,
+            original_x: block.x,
+            original_y: block.y,
+        });
+    }
+
+    let remaining = super::count_violations(&legal_positions, params);
+    if remaining > 0 {
+        println!("**** {} overlaps or out-of-core blocks remain", remaining);
+    }
+    legal_positions
+}
@@ -6,11 +6,29 @@
 // being used to refer back to the parent data structure (a subset
 // of cells from a BookshelfCircuit, for example).
 //
+pub mod bound;
+pub mod compact;
+pub mod detailed;
+pub mod dp;
+pub mod eco;
+pub mod feasibility;
+pub mod fixed;
+pub mod flip;
+pub mod flow;
+pub mod freespace;
+pub mod generate;
 pub mod hcwt_legal;
+pub mod limit;
+pub mod macros;
+pub mod partition;
+pub mod placerow;
+pub mod random;
 pub mod rowfill;
+pub mod spread;
 pub mod tetris;
 
 use scan_fmt::scan_fmt;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
@@ -23,12 +41,92 @@ use std::path::Path;
 use bookshelf_r::bookshelf::BookshelfCircuit;
 use pstools;
 
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum LegalKind {
     Tetris,
     HCwT,
+    RowFill,
+    Gaps, // Gap-aware Tetris
+    Dp,   // Exact row placement by dynamic programming
 }
 
+impl std::str::FromStr for LegalKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tetris" => Ok(LegalKind::Tetris),
+            "hcwt" => Ok(LegalKind::HCwT),
+            "rowfill" => Ok(LegalKind::RowFill),
+            "gaps" => Ok(LegalKind::Gaps),
+            "dp" => Ok(LegalKind::Dp),
+            _ => Err(format!(
+                "unknown legalizer {} (tetris, hcwt, rowfill, gaps, dp)",
+                s
+            )),
+        }
+    }
+}
+
+// Run one of the legalizers, with its default settings, around the
+// fixed positions.  Gap-aware Tetris and Dp place the blocks around
+// them; for the others, blocks that land on one are moved off it.
+pub fn legalize_kind(lp: &LegalProblem, kind: LegalKind, fixed: &Vec<LegalPosition>) -> Vec<LegalPosition> {
+    let config = tetris::TetrisConfig::default();
+    let legalizer = |lp: &LegalProblem| match kind {
+        LegalKind::Tetris => tetris::legalize(lp),
+        LegalKind::HCwT => hcwt_legal::legalize(lp),
+        LegalKind::RowFill => rowfill::legalize(lp),
+        LegalKind::Gaps => tetris::legalize_gaps(lp, &config, fixed),
+        LegalKind::Dp => dp::legalize_fixed(lp, fixed),
+    };
+    fixed::legalize(lp, fixed, &config, &legalizer)
+}
+
+// Reasons a problem cannot be (or may be hard to) legalize
+#[derive(Clone, Debug, PartialEq)]
+pub enum LegalError {
+    EmptyCore, // No rows, or no sites in a row
+    OverCapacity { area: f32, capacity: f32 },
+    TooWide { tag: usize, w: f32, row_length: f32 },
+    TooTall { tag: usize, h: f32, core_height: f32 },
+    RegionOverCapacity { origin_y: f32, area: f32, capacity: f32 },
+}
+
+impl fmt::Display for LegalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LegalError::EmptyCore => write!(f, "core has no rows or no sites"),
+            LegalError::OverCapacity { area, capacity } => write!(
+                f,
+                "block area {:.1} exceeds core capacity {:.1}",
+                area, capacity
+            ),
+            LegalError::TooWide { tag, w, row_length } => write!(
+                f,
+                "block {} width {:.1} is longer than a row ({:.1})",
+                tag, w, row_length
+            ),
+            LegalError::TooTall { tag, h, core_height } => write!(
+                f,
+                "block {} height {:.1} is taller than the core ({:.1})",
+                tag, h, core_height
+            ),
+            LegalError::RegionOverCapacity {
+                origin_y,
+                area,
+                capacity,
+            } => write!(
+                f,
+                "rows from {:.1} want {:.1} of block area, capacity {:.1}",
+                origin_y, area, capacity
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LegalError {}
+
 #[derive(Copy, Clone)]
 pub struct LegalPosition {
     pub block_tag: usize, // Refers to the index of a LegalBlock
@@ -50,6 +148,104 @@ pub fn bounds(blocks: &Vec<LegalPosition>) -> pstools::bbox::BBox {
     bb
 }
 
+// Overlaps smaller than this are rounding noise, not violations
+const TOLERANCE: f32 = 1.0e-3;
+
+// Walk the violations in a legalization: f(i, None) for a block that
+// sticks out of the core, and f(i, Some(j)) for a pair that overlaps.
+fn walk_violations<F: FnMut(usize, Option<usize>)>(
+    legalization: &Vec<LegalPosition>,
+    params: &LegalParams,
+    mut f: F,
+) {
+    let left = params.origin_x;
+    let right = params.origin_x + params.grid_x as f32 * params.step_x;
+    let bottom = params.origin_y;
+    let top = params.origin_y + params.grid_y as f32 * params.step_y;
+
+    let mut sorted: Vec<usize> = (0..legalization.len()).collect();
+    sorted.sort_by(|&a, &b| {
+        legalization[a]
+            .x
+            .partial_cmp(&legalization[b].x)
+            .expect("Could not compare")
+    });
+
+    for (k, &i) in sorted.iter().enumerate() {
+        let a = &legalization[i];
+        if a.x < left - TOLERANCE
+            || a.x + a.w > right + TOLERANCE
+            || a.y < bottom - TOLERANCE
+            || a.y + a.h > top + TOLERANCE
+        {
+            f(i, None);
+        }
+        for &j in &sorted[k + 1..] {
+            let b = &legalization[j];
+            if b.x >= a.x + a.w - TOLERANCE {
+                break;
+            }
+            if b.y < a.y + a.h - TOLERANCE && a.y < b.y + b.h - TOLERANCE {
+                f(i, Some(j));
+            }
+        }
+    }
+}
+
+// Number of pairs of blocks that overlap, plus the number of blocks
+// that stick out of the core.  Zero means the legalization is legal.
+pub fn count_violations(legalization: &Vec<LegalPosition>, params: &LegalParams) -> usize {
+    let mut violations = 0;
+    walk_violations(legalization, params, |_, _| violations += 1);
+    violations
+}
+
+// Which of the positions overlap something, or stick out of the core
+pub fn find_violations(legalization: &Vec<LegalPosition>, params: &LegalParams) -> Vec<bool> {
+    let mut bad = vec![false; legalization.len()];
+    walk_violations(legalization, params, |i, j| {
+        bad[i] = true;
+        if let Some(j) = j {
+            bad[j] = true;
+        }
+    });
+    bad
+}
+
+// Displacement summary for a legalization.  Displacement of each
+// block is the Manhattan distance between its original and
+// legalized lower left corners.
+#[derive(Copy, Clone)]
+pub struct Metrics {
+    pub total: f32,
+    pub max: f32,
+    pub average: f32,
+    pub max_tag: Option<usize>, // Block that moved the furthest
+}
+
+impl Metrics {
+    pub fn new(legalization: &Vec<LegalPosition>) -> Metrics {
+        let mut metrics = Metrics {
+            total: 0.0,
+            max: 0.0,
+            average: 0.0,
+            max_tag: None,
+        };
+        for pos in legalization {
+            let d = (pos.x - pos.original_x).abs() + (pos.y - pos.original_y).abs();
+            metrics.total += d;
+            if metrics.max_tag.is_none() || d > metrics.max {
+                metrics.max = d;
+                metrics.max_tag = Some(pos.block_tag);
+            }
+        }
+        if !legalization.is_empty() {
+            metrics.average = metrics.total / legalization.len() as f32;
+        }
+        metrics
+    }
+}
+
 // Convert
 #[derive(Copy, Clone)]
 pub struct LegalBlock {
@@ -58,6 +254,8 @@ pub struct LegalBlock {
     pub y: f32,
     pub h: f32, // Height and width of the block
     pub w: f32,
+    pub weight: f32,   // Cost of moving the block, relative to others (1.0 normally)
+    pub priority: i32, // Higher priority blocks are placed first (0 normally)
 }
 use std::cmp::Ordering;
 
@@ -81,6 +279,46 @@ impl PartialEq for LegalBlock {
 
 impl Eq for LegalBlock {}
 
+// The block at a position, with the weight and priority of the block
+// with the same tag (the defaults, if there is none)
+pub fn block_at(pos: &LegalPosition, blocks: &HashMap<usize, &LegalBlock>) -> LegalBlock {
+    let (weight, priority) = blocks
+        .get(&pos.block_tag)
+        .map_or((1.0, 0), |b| (b.weight, b.priority));
+    LegalBlock {
+        tag: pos.block_tag,
+        x: pos.x,
+        y: pos.y,
+        h: pos.h,
+        w: pos.w,
+        weight,
+        priority,
+    }
+}
+
+// Deterministic orderings.  Every legalizer that sorts blocks by
+// position breaks ties by tag, so that the result does not depend on
+// the order the blocks were given in.
+pub fn legal_block_cmp_y(a: &LegalBlock, b: &LegalBlock) -> Ordering {
+    if a.y < b.y {
+        return Ordering::Less;
+    }
+    if a.y > b.y {
+        return Ordering::Greater;
+    }
+    a.tag.cmp(&b.tag)
+}
+
+pub fn legal_block_cmp_x(a: &LegalBlock, b: &LegalBlock) -> Ordering {
+    if a.x < b.x {
+        return Ordering::Less;
+    }
+    if a.x > b.x {
+        return Ordering::Greater;
+    }
+    a.tag.cmp(&b.tag)
+}
+
 #[derive(Copy, Clone)]
 pub struct LegalParams {
     pub grid_x: usize,
@@ -99,7 +337,15 @@ pub struct LegalProblem {
     pub params: LegalParams,
 }
 
+// Load a problem.  Fixed blocks are left out; load_fixed returns them
+// as well.
 pub fn load(filename: &String) -> LegalProblem {
+    load_fixed(filename).0
+}
+
+// Load a problem, and the blocks marked fixed, as positions that
+// cannot move
+pub fn load_fixed(filename: &String) -> (LegalProblem, Vec<LegalPosition>) {
     let f = File::open(filename).unwrap();
     let mut reader = BufReader::with_capacity(32000, f);
 
@@ -121,23 +367,45 @@ pub fn load(filename: &String) -> LegalProblem {
         },
     };
 
+    let mut fixed = Vec::new();
     let line = getline(&mut reader).unwrap();
     let (num_blocks) = scan_fmt!(&line, "{}", usize).unwrap();
     for _i in 0..num_blocks {
         let line = getline(&mut reader).unwrap();
         let (tag, x, y, w, h) =
             scan_fmt!(&line, "{} {} {} {} {}", usize, f32, f32, f32, f32).unwrap();
+        // A block ending in "fixed" cannot move
+        let extra: Vec<&str> = line.split_whitespace().skip(5).collect();
+        if extra.last() == Some(&"fixed") {
+            fixed.push(LegalPosition {
+                block_tag: tag,
+                x,
+                y,
+                h,
+                w,
+                original_x: x,
+                original_y: y,
+            });
+            continue;
+        }
+        // Weight and priority are optional, after the size
+        let mut extra = extra.into_iter();
+        let weight = extra.next().map(|s| s.parse::<f32>().unwrap()).unwrap_or(1.0);
+        let priority = extra.next().map(|s| s.parse::<i32>().unwrap()).unwrap_or(0);
         lp.blocks.push(LegalBlock {
             tag: tag,
             x: x,
             y: y,
             h: h,
             w: w,
+            weight: weight,
+            priority: priority,
         });
     }
 
-    lp
+    (lp, fixed)
 }
+
 /*
 impl LegalProblem {
     pub fn postscript(&self, filename: &String, legalization: Vec<LegalPosition>) {
@@ -163,10 +431,17 @@ impl LegalProblem {
 
 impl LegalProblem {
     pub fn save(&self, filepath: &String) {
+        self.save_fixed(filepath, &Vec::new());
+    }
+
+    // Save, with the fixed positions marked as fixed, in the format
+    // that load_fixed reads
+    pub fn save_fixed(&self, filepath: &String, fixed: &Vec<LegalPosition>) {
         let mut f;
 
         // if the file path is empty, just print to standard out
         f = Box::new(File::create(filepath).unwrap()) as Box<dyn Write>;
+        writeln!(&mut f, "# Number of grids in X, Y.  Origin, then the step size in X and Y directions").unwrap();
         writeln!(
             &mut f,
             "{} {} {} {} {} {}",
@@ -177,9 +452,22 @@ impl LegalProblem {
             self.params.step_x,
             self.params.step_y
         ).unwrap();
-        writeln!(&mut f, "{}", self.blocks.len()).unwrap();
+        writeln!(&mut f, "# Number of blocks").unwrap();
+        writeln!(&mut f, "{}", self.blocks.len() + fixed.len()).unwrap();
+        writeln!(&mut f, "# For each block, the tag, X and Y positions, then width and height").unwrap();
+        writeln!(&mut f, "# (and optionally the weight and priority, or \"fixed\")").unwrap();
+        for p in fixed {
+            writeln!(&mut f, "{} {} {} {} {} fixed", p.block_tag, p.x, p.y, p.w, p.h).unwrap();
+        }
         for b in &self.blocks {
-            writeln!(&mut f, "{} {} {} {} {}", b.tag, b.x, b.y, b.w, b.h).unwrap();
+            write!(&mut f, "{} {} {} {} {}", b.tag, b.x, b.y, b.w, b.h).unwrap();
+            // Weight and priority only when they are not the defaults
+            if b.priority != 0 {
+                write!(&mut f, " {} {}", b.weight, b.priority).unwrap();
+            } else if b.weight != 1.0 {
+                write!(&mut f, " {}", b.weight).unwrap();
+            }
+            writeln!(&mut f).unwrap();
         }
     }
     pub fn postscript(&self, filename: &String, legalization: &Vec<LegalPosition>) {
@@ -323,11 +611,13 @@ impl LegalProblem {
 
     pub fn new_from(&self, positions: &Vec<LegalPosition>) -> LegalProblem {
         let mut new_lp = self.clone();
-        
+        // Weight and priority carry over from the block with the same tag
+        let by_tag: HashMap<usize, &LegalBlock> = self.blocks.iter().map(|b| (b.tag, b)).collect();
+
         new_lp.blocks = Vec::new();
         for pos in positions {
-            new_lp.blocks.push(LegalBlock { tag: pos.block_tag, x: pos.x, y: pos.y, h: pos.h, w: pos.w });
-        }        
+            new_lp.blocks.push(block_at(pos, &by_tag));
+        }
 
         new_lp
     }
@@ -349,6 +639,54 @@ impl LegalProblem {
             std::mem::swap(&mut b.h, &mut b.w);
         }
     }
+    // Split the core into horizontal bands of whole rows, and hand
+    // each block to the band that contains its preferred row.  Bands
+    // are never shorter than the tallest block, so every block fits
+    // in the band it is given to.
+    pub fn split_rows(&self, bands: usize) -> Vec<LegalProblem> {
+        let mut tallest = 1;
+        for block in &self.blocks {
+            tallest = tallest.max((block.h / self.params.step_y).ceil() as usize);
+        }
+        let bands = bands.max(1);
+        let rows_per_band = ((self.params.grid_y + bands - 1) / bands).max(tallest);
+
+        let mut result = Vec::new();
+        let mut row = 0;
+        while row < self.params.grid_y {
+            let rows = rows_per_band.min(self.params.grid_y - row);
+            if rows < tallest && !result.is_empty() {
+                // Short last band gets folded into the one below it
+                let last: &mut LegalProblem = result.last_mut().unwrap();
+                last.params.grid_y += rows;
+                break;
+            }
+            let mut band = LegalProblem {
+                blocks: Vec::new(),
+                params: self.params,
+            };
+            band.params.origin_y = self.params.origin_y + row as f32 * self.params.step_y;
+            band.params.grid_y = rows;
+            result.push(band);
+            row += rows;
+        }
+        if result.is_empty() {
+            result.push(LegalProblem {
+                blocks: Vec::new(),
+                params: self.params,
+            });
+        }
+
+        for block in &self.blocks {
+            let row = ((block.y - self.params.origin_y) / self.params.step_y).round();
+            let row = (row.max(0.0) as usize).min(self.params.grid_y.saturating_sub(1));
+            let band = (row / rows_per_band).min(result.len() - 1);
+            result[band].blocks.push(*block);
+        }
+
+        result
+    }
+
     pub fn bounds(&self) -> pstools::bbox::BBox {
         let mut bbox = pstools::bbox::BBox::new();
         for block in &self.blocks {
@@ -409,29 +747,44 @@ impl LegalProblem {
         }
     }
 
-    pub fn pack_west(&mut self) {
-        let leg = legalize_floorplan(self);
-        self.move_blocks(&leg);
+    // Compaction in each direction, with the constraint-graph engine
+    // in compact.rs.  Each returns the critical chain.
+    pub fn pack(&mut self, direction: compact::CompactDirection) -> compact::Compaction {
+        let compaction = compact::compact(self, direction, 0.0);
+        self.place_in_order(&compaction.positions);
+        compaction
+    }
+
+    pub fn pack_west(&mut self) -> compact::Compaction {
+        self.pack(compact::CompactDirection::West)
+    }
+
+    pub fn pack_east(&mut self) -> compact::Compaction {
+        self.pack(compact::CompactDirection::East)
     }
 
-    pub fn pack_east(&mut self) {
-        self.mirror_x();
-        let leg = legalize_floorplan(self);
-        self.move_blocks(&leg);
-        self.mirror_x();
+    pub fn pack_south(&mut self) -> compact::Compaction {
+        self.pack(compact::CompactDirection::South)
     }
 
-    pub fn pack_south(&mut self) {
-        self.rotate();
-        let leg = legalize_floorplan(self);
-        self.move_blocks(&leg);
-        self.rotate();
+    pub fn pack_north(&mut self) -> compact::Compaction {
+        self.pack(compact::CompactDirection::North)
     }
 
-    pub fn pack_north(&mut self) {
-        self.mirror_y();
-        self.pack_south();
-        self.mirror_y();
+    // Pull everything in toward a point
+    pub fn pack_toward(&mut self, x: f32, y: f32) -> compact::Compaction {
+        let compaction = compact::compact_toward(self, x, y, 0.0);
+        self.place_in_order(&compaction.positions);
+        compaction
+    }
+
+    // Move the blocks to positions given in the same order as the
+    // blocks, whatever their tags
+    fn place_in_order(&mut self, positions: &Vec<LegalPosition>) {
+        for (block, pos) in self.blocks.iter_mut().zip(positions) {
+            block.x = pos.x;
+            block.y = pos.y;
+        }
     }
 }
 
@@ -470,6 +823,8 @@ pub fn legalize_circuit(bc: &mut BookshelfCircuit, kind: LegalKind) {
                 y: bc.cellpos[c].y,
                 h: bc.cells[c].h,
                 w: bc.cells[c].w,
+                weight: 1.0,
+                priority: 0,
             });
         }
     }
@@ -504,8 +859,6 @@ pub fn legalize_circuit(bc: &mut BookshelfCircuit, kind: LegalKind) {
 
 use std::fmt;
 
-use crate::legalize::tetris::legalize_floorplan;
-
 impl fmt::Display for LegalParams {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
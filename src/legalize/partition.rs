@@ -0,0 +1,158 @@
+// Partitioned legalization.  The core is split into horizontal bands
+// of whole rows, and each band into columns of whole sites, with the
+// cuts placed so that every tile holds about the same cell area.  Each
+// tile is legalized on its own thread, with any of the legalizers, and
+// the results are stitched back together.  Tiles can overflow into
+// their neighbours, so anything that overlaps or sticks out of the
+// core afterwards is ripped up and dropped into the nearest free gap
+// (gap-aware Tetris, with everything else fixed).
+//
+// With a single tile, the legalizer just runs on the whole problem,
+// so the result is exactly the serial one.
+
+use super::fixed;
+use super::tetris::TetrisConfig;
+use super::{LegalBlock, LegalKind, LegalPosition, LegalProblem};
+use std::thread;
+
+// Cut 0..weights.len() into parts, each at least min_size long, at the
+// quantiles of the weights.  Returns the cut points, starting with 0
+// and ending with the length.
+fn quantiles(weights: &Vec<f32>, parts: usize, min_size: usize) -> Vec<usize> {
+    let len = weights.len();
+    let min_size = min_size.max(1);
+    let parts = parts.max(1).min((len / min_size).max(1));
+    let total: f32 = weights.iter().sum();
+
+    let mut cuts = vec![0];
+    let mut cumulative = 0.0;
+    let mut i = 0;
+    for p in 1..parts {
+        let target = total * p as f32 / parts as f32;
+        while i < len && cumulative + weights[i] <= target {
+            cumulative += weights[i];
+            i += 1;
+        }
+        let low = cuts[p - 1] + min_size;
+        let high = len - (parts - p) * min_size;
+        let cut = i.clamp(low, high);
+        while i < cut {
+            cumulative += weights[i];
+            i += 1;
+        }
+        cuts.push(cut);
+    }
+    cuts.push(len);
+    cuts
+}
+
+fn block_row(lp: &LegalProblem, block: &LegalBlock) -> usize {
+    let row = ((block.y - lp.params.origin_y) / lp.params.step_y).round();
+    (row.max(0.0) as usize).min(lp.params.grid_y.saturating_sub(1))
+}
+
+fn block_site(lp: &LegalProblem, block: &LegalBlock) -> usize {
+    let site = ((block.x + block.w / 2.0 - lp.params.origin_x) / lp.params.step_x).floor();
+    (site.max(0.0) as usize).min(lp.params.grid_x.saturating_sub(1))
+}
+
+// Split the problem into tiles: bands of rows, and columns of sites
+// within each band.  Bands are at least as tall as the tallest block,
+// and columns at least as wide as the widest block in the band.
+// Blocks keep their order within each tile.
+pub fn split(lp: &LegalProblem, bands: usize, columns: usize) -> Vec<LegalProblem> {
+    let params = &lp.params;
+    let mut tallest = 1;
+    let mut row_area = vec![0.0; params.grid_y];
+    for block in &lp.blocks {
+        tallest = tallest.max((block.h / params.step_y).ceil() as usize);
+        row_area[block_row(lp, block)] += block.w * block.h;
+    }
+
+    let row_cuts = quantiles(&row_area, bands, tallest);
+    let mut tiles = Vec::new();
+    for band in row_cuts.windows(2) {
+        let in_band: Vec<&LegalBlock> = lp
+            .blocks
+            .iter()
+            .filter(|b| (band[0]..band[1]).contains(&block_row(lp, b)))
+            .collect();
+
+        let mut widest = 1;
+        let mut site_area = vec![0.0; params.grid_x];
+        for block in &in_band {
+            widest = widest.max((block.w / params.step_x).ceil() as usize);
+            site_area[block_site(lp, block)] += block.w * block.h;
+        }
+
+        let site_cuts = quantiles(&site_area, columns, widest);
+        for column in site_cuts.windows(2) {
+            let mut tile = LegalProblem {
+                blocks: Vec::new(),
+                params: *params,
+            };
+            tile.params.origin_x = params.origin_x + column[0] as f32 * params.step_x;
+            tile.params.grid_x = column[1] - column[0];
+            tile.params.origin_y = params.origin_y + band[0] as f32 * params.step_y;
+            tile.params.grid_y = band[1] - band[0];
+            for block in &in_band {
+                if (column[0]..column[1]).contains(&block_site(lp, block)) {
+                    tile.blocks.push(**block);
+                }
+            }
+            tiles.push(tile);
+        }
+    }
+    tiles
+}
+
+// Legalize each tile in parallel with the legalizer given, then repair
+// the boundaries between them, around the fixed positions, with the
+// Tetris settings given.  A legalizer that can place blocks around the
+// fixed positions is handed them by the caller.
+pub fn legalize<F>(
+    lp: &LegalProblem,
+    fixed: &Vec<LegalPosition>,
+    config: &TetrisConfig,
+    bands: usize,
+    columns: usize,
+    legalizer: &F,
+) -> Vec<LegalPosition>
+where
+    F: Fn(&LegalProblem) -> Vec<LegalPosition> + Sync,
+{
+    if bands.max(1) * columns.max(1) <= 1 {
+        return legalizer(lp);
+    }
+    let tiles = split(lp, bands, columns);
+    if tiles.len() <= 1 {
+        return legalizer(lp);
+    }
+
+    let positions = thread::scope(|s| {
+        let handles: Vec<_> = tiles
+            .iter()
+            .filter(|tile| !tile.blocks.is_empty())
+            .map(|tile| s.spawn(move || legalizer(tile)))
+            .collect();
+        let mut positions = Vec::with_capacity(lp.blocks.len());
+        for handle in handles {
+            positions.extend(handle.join().expect("Tile legalizer panicked"));
+        }
+        positions
+    });
+
+    fixed::repair(lp, positions, fixed, config)
+}
+
+// Partitioned legalization with one of the registered legalizers
+pub fn legalize_kind(
+    lp: &LegalProblem,
+    fixed: &Vec<LegalPosition>,
+    config: &TetrisConfig,
+    kind: LegalKind,
+    bands: usize,
+    columns: usize,
+) -> Vec<LegalPosition> {
+    legalize(lp, fixed, config, bands, columns, &|tile: &LegalProblem| super::legalize_kind(tile, kind, fixed))
+}
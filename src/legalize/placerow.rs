@@ -0,0 +1,92 @@
+// Place the cells of one row segment, in a fixed order, at the x
+// positions that minimize total displacement without overlap (the
+// PlaceRow step of Abacus).  Each cell starts as its own cluster at
+// its preferred x; a cluster that overlaps the one to its left is
+// merged into it, and the merged cluster moves to the weighted
+// average of where its cells want to be.  Clusters are kept inside
+// [start, limit] -- unless the cells are wider than the segment, in
+// which case they start at `start` and run past the limit.
+//
+// Cells are weighted by their width times their weight, so a large
+// cell, or one that is expensive to move, moves less.
+
+use super::{LegalBlock, LegalPosition};
+
+struct Cluster {
+    first: usize, // Index of the first cell in the cluster
+    weight: f32,
+    q: f32, // Weighted sum of preferred positions, less offsets
+    width: f32,
+    x: f32,
+}
+
+// X positions for the blocks, in the order given (normally sorted by x)
+pub fn place_row(blocks: &Vec<LegalBlock>, start: f32, limit: f32) -> Vec<f32> {
+    let mut clusters: Vec<Cluster> = Vec::new();
+    for (i, block) in blocks.iter().enumerate() {
+        let weight = (block.w * block.weight).max(f32::MIN_POSITIVE);
+        let mut cluster = Cluster {
+            first: i,
+            weight,
+            q: weight * block.x,
+            width: block.w,
+            x: 0.0,
+        };
+        loop {
+            cluster.x = (cluster.q / cluster.weight)
+                .min(limit - cluster.width)
+                .max(start);
+            match clusters.last() {
+                Some(prev) if prev.x + prev.width > cluster.x => {
+                    let prev = clusters.pop().unwrap();
+                    cluster = Cluster {
+                        first: prev.first,
+                        weight: prev.weight + cluster.weight,
+                        q: prev.q + cluster.q - cluster.weight * prev.width,
+                        width: prev.width + cluster.width,
+                        x: 0.0,
+                    };
+                }
+                _ => break,
+            }
+        }
+        clusters.push(cluster);
+    }
+
+    let mut xs = vec![0.0; blocks.len()];
+    for (c, cluster) in clusters.iter().enumerate() {
+        let end = if c + 1 < clusters.len() {
+            clusters[c + 1].first
+        } else {
+            blocks.len()
+        };
+        let mut x = cluster.x;
+        for (slot, block) in xs[cluster.first..end].iter_mut().zip(&blocks[cluster.first..end]) {
+            *slot = x;
+            x += block.w;
+        }
+    }
+    xs
+}
+
+// Place a row segment at height y, and add the results to positions
+pub fn emit_row(
+    blocks: &Vec<LegalBlock>,
+    start: f32,
+    limit: f32,
+    y: f32,
+    positions: &mut Vec<LegalPosition>,
+) {
+    let xs = place_row(blocks, start, limit);
+    for (block, x) in blocks.iter().zip(xs) {
+        positions.push(LegalPosition {
+            block_tag: block.tag,
+            x,
+            y,
+            h: block.h,
+            w: block.w,
+            original_x: block.x,
+            original_y: block.y,
+        });
+    }
+}
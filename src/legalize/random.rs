@@ -0,0 +1,120 @@
+// Randomized legalization, for exploring alternative solutions.  The
+// legalizers are deterministic -- the same blocks give the same result,
+// whatever order they come in -- so to find other solutions, the
+// preferred positions are jittered a little before legalizing.  That
+// changes the order the blocks are taken in and which of several
+// nearly equal spots each one gets.  Displacement is still measured
+// from the real preferred positions, and the best of several tries is
+// kept.  Everything comes from a seed, so a run can be repeated.
+
+use super::spread;
+use super::{count_violations, LegalPosition, LegalProblem, Metrics};
+
+// Xorshift generator, so that randomized runs can be repeated from a
+// seed without pulling in another crate
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // Scramble the seed (splitmix64), so that nearby seeds give
+        // unrelated sequences; the state must not be zero
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        Rng {
+            state: if z == 0 { 1 } else { z },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        x
+    }
+
+    // Uniform in [0, 1)
+    pub fn uniform(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    // Uniform in [low, high)
+    pub fn range(&mut self, low: f32, high: f32) -> f32 {
+        low + (high - low) * self.uniform()
+    }
+
+    // Standard normal (Box-Muller)
+    pub fn normal(&mut self) -> f32 {
+        let u = 1.0 - self.uniform();
+        let v = self.uniform();
+        (-2.0 * u.ln()).sqrt() * (2.0 * std::f32::consts::PI * v).cos()
+    }
+
+    // Uniform in 0..n
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n.max(1) as u64) as usize
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct RandomConfig {
+    pub seed: u64,
+    pub tries: usize, // Including the first, unjittered, one
+    pub jitter: f32,  // Largest change to a preferred position, in row heights
+}
+
+impl Default for RandomConfig {
+    fn default() -> Self {
+        RandomConfig {
+            seed: 1,
+            tries: 8,
+            jitter: 0.5,
+        }
+    }
+}
+
+// The same problem, with each preferred position moved by up to
+// amount in x and y.  How far a block moves depends only on the seed
+// and its tag, not on where it is in the list.
+pub fn perturb(lp: &LegalProblem, seed: u64, amount: f32) -> LegalProblem {
+    let mut perturbed = lp.clone();
+    for block in perturbed.blocks.iter_mut() {
+        let mut rng = Rng::new(seed ^ (block.tag as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        block.x += rng.range(-amount, amount);
+        block.y += rng.range(-amount, amount);
+    }
+    perturbed
+}
+
+// Legalize the problem as given, and then with jittered positions, and
+// keep the result with the fewest violations and then the least
+// displacement
+pub fn legalize<F>(lp: &LegalProblem, config: &RandomConfig, legalizer: &F) -> Vec<LegalPosition>
+where
+    F: Fn(&LegalProblem) -> Vec<LegalPosition>,
+{
+    let mut best = legalizer(lp);
+    let mut best_score = (count_violations(&best, &lp.params), Metrics::new(&best).total);
+    let mut rng = Rng::new(config.seed);
+    for _try in 1..config.tries {
+        let perturbed = perturb(lp, rng.next_u64(), config.jitter * lp.params.step_y);
+        let mut result = legalizer(&perturbed);
+        spread::restore(lp, &mut result);
+        let score = (count_violations(&result, &lp.params), Metrics::new(&result).total);
+        #[cfg(feature = "ldbg")]
+        println!(
+            "Random try {}: {} violations, displacement {:.1}",
+            _try, score.0, score.1
+        );
+        if score.0 < best_score.0 || (score.0 == best_score.0 && score.1 < best_score.1) {
+            best_score = score;
+            best = result;
+        }
+    }
+    best
+}
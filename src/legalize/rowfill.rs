@@ -3,31 +3,46 @@
 // a target row length.
 // The target row length is simply the total width
 // of the blocks, divided by the number of rows.
+//
+// Each row is placed with placerow (least displacement, anywhere in
+// the row), or with legalize_exact, on sites by dp::place_row -- the
+// exact placement, where the row fits on sites, and its table fits in
+// dp::MAX_TABLE.
 
 // use bookshelf_r::bookshelf::BookshelfCircuit;
+use super::dp;
+use super::placerow;
+use super::{legal_block_cmp_x, legal_block_cmp_y};
 use super::{LegalBlock, LegalParams, LegalPosition, LegalProblem};
 
-fn make_row(row: &mut Vec<LegalBlock>, row_origin: f32, positions: &mut Vec<LegalPosition>) {
-    row.sort_by(|a, b| a.x.partial_cmp(&b.x).expect("Could not compare"));
-    let mut x = 0.0;
-    for b in row {
-        positions.push(LegalPosition {
-            block_tag: b.tag,
-            x: x,
-            y: row_origin,
-            h: b.h,
-            w: b.w,
-            original_x: b.x,
-            original_y: b.y,
-        });
-        x = x + b.w;
+fn make_row(row: &mut Vec<LegalBlock>, params: &LegalParams, row_origin: f32, exact: bool, positions: &mut Vec<LegalPosition>) {
+    row.sort_by(legal_block_cmp_x);
+    let right = params.origin_x + params.grid_x as f32 * params.step_x;
+    let y = params.origin_y + row_origin;
+    // Rows too big for the table go to placerow as well
+    if exact && dp::fits_table(row, params) {
+        if let Some(xs) = dp::place_row(row, params, &vec![false; params.grid_x], &dp::displacement) {
+            dp::emit_row((row_origin / params.step_y).round() as usize, row, xs, params, positions);
+            return;
+        }
+        // Too full for the sites; fall back to placerow
     }
+    placerow::emit_row(row, params.origin_x, right, y, positions);
 }
 
 pub fn legalize(lp: &LegalProblem) -> Vec<LegalPosition> {
+    fill(lp, false)
+}
+
+// Row fill, with each row placed exactly on sites
+pub fn legalize_exact(lp: &LegalProblem) -> Vec<LegalPosition> {
+    fill(lp, true)
+}
+
+fn fill(lp: &LegalProblem, exact: bool) -> Vec<LegalPosition> {
     let mut blocks = lp.blocks.clone();
 
-    blocks.sort_by(|a, b| a.y.partial_cmp(&b.y).expect("Could not compare"));
+    blocks.sort_by(legal_block_cmp_y);
     let nr = lp.params.grid_y;
 
     let mut total_width = 0.0;
@@ -45,7 +60,7 @@ pub fn legalize(lp: &LegalProblem) -> Vec<LegalPosition> {
 
     for b in &blocks {
         if width > target {
-            make_row(&mut row, rn * lp.params.step_y, &mut positions);
+            make_row(&mut row, &lp.params, rn * lp.params.step_y, exact, &mut positions);
             width = 0.0;
             rn += 1.0;
             #[cfg(feature="ldbg")]
@@ -56,7 +71,7 @@ pub fn legalize(lp: &LegalProblem) -> Vec<LegalPosition> {
         width += b.w;
     }
     // Last row
-    make_row(&mut row, rn * lp.params.step_y, &mut positions);
+    make_row(&mut row, &lp.params, rn * lp.params.step_y, exact, &mut positions);
 
     positions
 }
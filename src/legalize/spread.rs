@@ -0,0 +1,228 @@
+// Global spreading by min-cost flow.  The core is cut into bins a few
+// rows tall, and each bin can hold its area (less any macros) times the
+// target density.  Cell area flows from bins that are over capacity to
+// bins that are under, between neighbouring bins, with the cost of
+// each step the distance between bin centers; the min-cost flow gives
+// the least total movement that balances the density.  The flow is
+// then carried out by moving the cells nearest each bin boundary just
+// across it, most upstream bins first.
+//
+// The result is a LegalProblem with the same blocks at new preferred
+// positions, which any of the row legalizers can finish.  Displacement
+// should still be measured from where the blocks started, so
+// `legalize` puts the original positions back into the result.
+
+use super::compact;
+use super::flow::{FlowGraph, EPSILON};
+use super::macros;
+use super::{LegalBlock, LegalPosition, LegalProblem};
+use std::collections::HashMap;
+
+// Moving between rows costs slightly more, so that when there are
+// several equally short ways to spread, the flow stays within rows
+const VERTICAL_PREMIUM: f64 = 1.01;
+
+#[derive(Copy, Clone, Debug)]
+pub struct SpreadConfig {
+    pub bin_rows: usize,        // Bin height, in rows
+    pub bin_width: Option<f32>, // Bin width; square bins if not given
+    pub density: f32,           // Fraction of each bin that cells can fill
+}
+
+impl Default for SpreadConfig {
+    fn default() -> Self {
+        SpreadConfig {
+            bin_rows: 4,
+            bin_width: None,
+            density: 1.0,
+        }
+    }
+}
+
+// Move the blocks so that no bin is over capacity, with the least
+// total movement.  Macros stay where they are, and take up space.
+pub fn spread(lp: &LegalProblem, config: &SpreadConfig) -> LegalProblem {
+    let params = &lp.params;
+    let mut result = lp.clone();
+    if params.grid_y == 0 || params.grid_x == 0 || lp.blocks.is_empty() {
+        return result;
+    }
+
+    let core_left = params.origin_x;
+    let core_bottom = params.origin_y;
+    let core_right = core_left + params.grid_x as f32 * params.step_x;
+    let core_top = core_bottom + params.grid_y as f32 * params.step_y;
+    let bin_h = config.bin_rows.max(1) as f32 * params.step_y;
+    let bin_w = config.bin_width.unwrap_or(bin_h).max(params.step_x);
+    let nx = ((core_right - core_left) / bin_w).ceil().max(1.0) as usize;
+    let ny = params.grid_y.div_ceil(config.bin_rows.max(1));
+    let bins = nx * ny;
+
+    let left = |c: usize| core_left + c as f32 * bin_w;
+    let right = |c: usize| (core_left + (c + 1) as f32 * bin_w).min(core_right);
+    let bottom = |r: usize| core_bottom + r as f32 * bin_h;
+    let top = |r: usize| (core_bottom + (r + 1) as f32 * bin_h).min(core_top);
+    let bin_of = |b: &LegalBlock| {
+        let c = ((b.x + b.w / 2.0 - core_left) / bin_w).floor().max(0.0) as usize;
+        let r = ((b.y + b.h / 2.0 - core_bottom) / bin_h).floor().max(0.0) as usize;
+        r.min(ny - 1) * nx + c.min(nx - 1)
+    };
+
+    // Capacity of each bin, less the macros in it, and the cells in it
+    let mut capacity: Vec<f64> = (0..bins)
+        .map(|b| {
+            let (c, r) = (b % nx, b / nx);
+            ((right(c) - left(c)) * (top(r) - bottom(r)) * config.density) as f64
+        })
+        .collect();
+    let mut contents: Vec<Vec<usize>> = vec![Vec::new(); bins];
+    let mut used = vec![0.0f64; bins];
+    for (i, block) in lp.blocks.iter().enumerate() {
+        if !macros::is_macro(block, params) {
+            let b = bin_of(block);
+            contents[b].push(i);
+            used[b] += (block.w * block.h) as f64;
+            continue;
+        }
+        for (b, cap) in capacity.iter_mut().enumerate() {
+            let (c, r) = (b % nx, b / nx);
+            let ox = (block.x + block.w).min(right(c)) - block.x.max(left(c));
+            let oy = (block.y + block.h).min(top(r)) - block.y.max(bottom(r));
+            if ox > 0.0 && oy > 0.0 {
+                *cap -= (ox * oy) as f64;
+            }
+        }
+    }
+
+    // Bins, then the source and sink
+    let source = bins;
+    let sink = bins + 1;
+    let mut graph = FlowGraph::new(bins + 2);
+    let mut excess = 0.0;
+    for b in 0..bins {
+        let (c, r) = (b % nx, b / nx);
+        if c + 1 < nx {
+            graph.add_edge(b, b + 1, f64::MAX, bin_w as f64);
+            graph.add_edge(b + 1, b, f64::MAX, bin_w as f64);
+        }
+        if r + 1 < ny {
+            graph.add_edge(b, b + nx, f64::MAX, bin_h as f64 * VERTICAL_PREMIUM);
+            graph.add_edge(b + nx, b, f64::MAX, bin_h as f64 * VERTICAL_PREMIUM);
+        }
+        let over = used[b] - capacity[b].max(0.0);
+        if over > EPSILON {
+            graph.add_edge(source, b, over, 0.0);
+            excess += over;
+        } else if over < -EPSILON {
+            graph.add_edge(b, sink, -over, 0.0);
+        }
+    }
+    let (flow, _cost) = graph.solve(source, sink);
+    #[cfg(feature = "ldbg")]
+    println!(
+        "Spreading {} bins: {:.1} of {:.1} excess area moved at cost {:.1}",
+        bins, flow, excess, _cost
+    );
+    if excess - flow > 1.0 {
+        println!("**** Not enough space to spread {:.1} of the cell area", excess - flow);
+    }
+
+    // Net flow between neighbouring bins (forward edges are the ones
+    // with capacity)
+    let forward = |from: usize, to: usize| -> f64 {
+        graph.edges[from]
+            .iter()
+            .filter(|e| e.to == to && e.capacity > 0.0)
+            .map(|e| e.flow)
+            .sum()
+    };
+    let mut moves: Vec<Vec<(usize, f64)>> = vec![Vec::new(); bins];
+    let mut flow_edges = Vec::new();
+    for (b, out) in moves.iter_mut().enumerate() {
+        for e in graph.edges[b].iter().filter(|e| e.to < bins && e.capacity > 0.0) {
+            let net = e.flow - forward(e.to, b);
+            if net > EPSILON {
+                out.push((e.to, net));
+                flow_edges.push((b, e.to));
+            }
+        }
+        // Largest flows first
+        out.sort_by(|a, b| b.1.partial_cmp(&a.1).expect("Could not compare"));
+    }
+
+    // Carry out the flow, upstream bins first, moving the cells nearest
+    // each boundary across it
+    let blocks = &mut result.blocks;
+    for b in compact::topological(bins, &flow_edges) {
+        for &(to, amount) in &moves[b] {
+            let (c, r) = (b % nx, b / nx);
+            let (tc, tr) = (to % nx, to / nx);
+            // Distance of each cell from the boundary it is crossing
+            let key = |i: usize| {
+                let k = &blocks[i];
+                if tc > c {
+                    -(k.x + k.w)
+                } else if tc < c {
+                    k.x
+                } else if tr > r {
+                    -(k.y + k.h)
+                } else {
+                    k.y
+                }
+            };
+            let mut cells = std::mem::take(&mut contents[b]);
+            cells.sort_by(|&i, &j| {
+                key(i)
+                    .partial_cmp(&key(j))
+                    .expect("Could not compare")
+                    .then(blocks[i].tag.cmp(&blocks[j].tag))
+            });
+            let mut moved = 0.0;
+            let mut keep = Vec::with_capacity(cells.len());
+            for i in cells {
+                let area = (blocks[i].w * blocks[i].h) as f64;
+                if moved + area / 2.0 > amount {
+                    keep.push(i);
+                    continue;
+                }
+                moved += area;
+                let k = &mut blocks[i];
+                if tc > c {
+                    k.x = k.x.max(left(tc));
+                } else if tc < c {
+                    k.x = k.x.min(right(tc) - k.w);
+                } else if tr > r {
+                    k.y = k.y.max(bottom(tr));
+                } else {
+                    k.y = k.y.min(top(tr) - k.h);
+                }
+                contents[to].push(i);
+            }
+            contents[b] = keep;
+        }
+    }
+    result
+}
+
+// Put the original positions of the blocks back into a legalization of
+// a spread (or otherwise moved) problem
+pub fn restore(lp: &LegalProblem, legalization: &mut Vec<LegalPosition>) {
+    let original: HashMap<usize, (f32, f32)> = lp.blocks.iter().map(|b| (b.tag, (b.x, b.y))).collect();
+    for pos in legalization.iter_mut() {
+        if let Some(&(x, y)) = original.get(&pos.block_tag) {
+            pos.original_x = x;
+            pos.original_y = y;
+        }
+    }
+}
+
+// Spread, then legalize with the legalizer given
+pub fn legalize<F>(lp: &LegalProblem, config: &SpreadConfig, legalizer: &F) -> Vec<LegalPosition>
+where
+    F: Fn(&LegalProblem) -> Vec<LegalPosition>,
+{
+    let spread = spread(lp, config);
+    let mut legalization = legalizer(&spread);
+    restore(lp, &mut legalization);
+    legalization
+}
@@ -1,7 +1,297 @@
-use super::{LegalBlock, LegalParams, LegalPosition, LegalProblem};
+use super::freespace::FreeSpace;
+use super::macros;
+use super::placerow;
+use super::{legal_block_cmp_x, legal_block_cmp_y};
+use super::{LegalBlock, LegalParams, LegalPosition, LegalProblem, Metrics};
 use bookshelf_r::bookshelf::BookshelfCircuit;
+use std::str::FromStr;
+
+// Which side of the core the blocks get dropped against.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TetrisDirection {
+    Left,  // Classic Tetris, pack toward the left edge
+    Right, // Flip, pack, flip -- pack toward the right edge
+    Both,  // Run both, keep the better result in each region
+    Inward,  // Pack from both edges toward the middle
+    Outward, // Fill each row from its center toward the edges
+    Gaps,    // Drop into the nearest free gap that fits
+}
+
+impl FromStr for TetrisDirection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "left" => Ok(TetrisDirection::Left),
+            "right" => Ok(TetrisDirection::Right),
+            "both" => Ok(TetrisDirection::Both),
+            "inward" => Ok(TetrisDirection::Inward),
+            "outward" => Ok(TetrisDirection::Outward),
+            "gaps" => Ok(TetrisDirection::Gaps),
+            _ => Err(format!(
+                "unknown Tetris direction {} (left, right, both, inward, outward, gaps)",
+                s
+            )),
+        }
+    }
+}
+
+// Tetris legalization in a given direction.  With "both", the core is
+// split into horizontal bands of rows (regions), each band is legalized
+// to the left and to the right, and the lower displacement result for
+// that band is kept.  Gap-aware Tetris places the blocks around the
+// fixed positions; the frontier directions do not know about them, so
+// anything they put on a fixed block is moved to the nearest gap.
+pub fn legalize_direction(
+    lp: &LegalProblem,
+    direction: TetrisDirection,
+    regions: usize,
+    config: &TetrisConfig,
+    fixed: &Vec<LegalPosition>,
+) -> Vec<LegalPosition> {
+    super::fixed::legalize(lp, fixed, config, &|lp: &LegalProblem| match direction {
+        TetrisDirection::Left => legalize_config(lp, config),
+        TetrisDirection::Right => super::flip::legalize_config(lp, config),
+        TetrisDirection::Inward => legalize_inward(lp, config),
+        TetrisDirection::Outward => legalize_outward(lp, config),
+        TetrisDirection::Gaps => legalize_gaps(lp, config, fixed),
+        TetrisDirection::Both => {
+            let mut legal_positions = Vec::new();
+            for band in lp.split_rows(regions) {
+                let left = legalize_config(&band, config);
+                let right = super::flip::legalize_config(&band, config);
+                #[cfg(feature = "ldbg")]
+                println!(
+                    "Band at {}: left {} right {}",
+                    band.params.origin_y,
+                    Metrics::new(&left).total,
+                    Metrics::new(&right).total
+                );
+                if Metrics::new(&right).total < Metrics::new(&left).total {
+                    legal_positions.extend(right);
+                } else {
+                    legal_positions.extend(left);
+                }
+            }
+            legal_positions
+        }
+    })
+}
+
+// How the row congestion penalty grows with the number of blocks
+// already in a row.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CongestionCurve {
+    Linear,         // beta * usage
+    Quadratic(f32), // beta * (1 + usage / scale) * usage
+}
+
+impl FromStr for CongestionCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "linear" {
+            return Ok(CongestionCurve::Linear);
+        }
+        if s == "quadratic" {
+            return Ok(CongestionCurve::Quadratic(10.0));
+        }
+        if let Some(scale) = s.strip_prefix("quadratic:") {
+            if let Ok(scale) = scale.parse::<f32>() {
+                return Ok(CongestionCurve::Quadratic(scale));
+            }
+        }
+        Err(format!(
+            "unknown congestion curve {} (linear, quadratic, quadratic:<scale>)",
+            s
+        ))
+    }
+}
+
+// How the horizontal and vertical movement of a block are combined.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DisplacementNorm {
+    L1,      // dy + alpha * |dx|
+    L2,      // sqrt(dy^2 + (alpha * dx)^2)
+    Squared, // dy^2 + (alpha * dx)^2
+}
+
+impl FromStr for DisplacementNorm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "l1" => Ok(DisplacementNorm::L1),
+            "l2" => Ok(DisplacementNorm::L2),
+            "squared" => Ok(DisplacementNorm::Squared),
+            _ => Err(format!("unknown displacement norm {} (l1, l2, squared)", s)),
+        }
+    }
+}
+
+// How a limit on the displacement of each block is enforced.  Hard
+// limits rule out any spot beyond the limit, unless there is nothing
+// closer; soft limits add a penalty for each unit past it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DisplacementLimit {
+    Hard,
+    Soft(f32), // Penalty per unit of displacement over the limit
+}
+
+impl FromStr for DisplacementLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "hard" {
+            return Ok(DisplacementLimit::Hard);
+        }
+        if s == "soft" {
+            return Ok(DisplacementLimit::Soft(10.0));
+        }
+        if let Some(weight) = s.strip_prefix("soft:") {
+            if let Ok(weight) = weight.parse::<f32>() {
+                return Ok(DisplacementLimit::Soft(weight));
+            }
+        }
+        Err(format!("unknown displacement limit {} (hard, soft, soft:<weight>)", s))
+    }
+}
+
+// Cost added to a spot past a hard displacement limit, so that any
+// spot within the limit is cheaper
+const HARD_LIMIT_COST: f32 = 1.0e6;
+
+// Tuning for the Tetris cost model.  The default is the original
+// hard-coded behaviour.  The direction-sensitive factors
+// (alpha_left, alpha_right) come from the LegalParams.
+#[derive(Copy, Clone, Debug)]
+pub struct TetrisConfig {
+    pub search_percent: usize, // Rows searched above and below, percent of all rows
+    pub search_min: usize,     // ...but never fewer than this
+    pub beta: f32,             // Row congestion penalty coefficient
+    pub congestion: CongestionCurve,
+    pub norm: DisplacementNorm,
+    pub height_penalty: f32, // Per unit of block height not filling its rows
+    pub max_displacement: Option<f32>, // Limit on how far any block moves
+    pub limit: DisplacementLimit,      // ...and how it is enforced
+}
+
+impl Default for TetrisConfig {
+    fn default() -> Self {
+        TetrisConfig {
+            search_percent: 5,
+            search_min: 5,
+            beta: 0.5,
+            congestion: CongestionCurve::Quadratic(10.0),
+            norm: DisplacementNorm::L1,
+            height_penalty: 0.1,
+            max_displacement: None,
+            limit: DisplacementLimit::Hard,
+        }
+    }
+}
+
+// Number of rows a block spans, and the range of rows (lowest
+// starting row, highest starting row) that is searched for it.
+fn search_rows(
+    block: &LegalBlock,
+    params: &LegalParams,
+    config: &TetrisConfig,
+) -> (usize, usize, usize, usize) {
+    // Modified: Use ceil() to calculate required rows and ensure minimum 1 row
+    let block_rows = (block.h / params.step_y).ceil() as usize;
+    let block_rows = block_rows.max(1); // Ensure at least 1 row
+
+    // Modified: Dynamic search range calculation with floor() for safety
+    let best_row = ((block.y - params.origin_y) / params.step_y).floor() as usize;
+    let best_row = best_row.min(params.grid_y.saturating_sub(block_rows));
+    let search_radius = (config.search_percent * params.grid_y / 100).max(config.search_min);
+    let low_row = best_row.saturating_sub(search_radius);
+    let high_row = (best_row + search_radius).min(params.grid_y.saturating_sub(block_rows));
+
+    (block_rows, best_row, low_row, high_row)
+}
+
+// Cost of dropping a block at x in a row.  All of the Tetris variants
+// share this: direction-sensitive horizontal movement and vertical
+// movement (scaled by the weight of the block), a penalty for rows
+// that are already crowded, and one for moving past the displacement
+// limit, if there is one.
+fn placement_cost(
+    block: &LegalBlock,
+    params: &LegalParams,
+    config: &TetrisConfig,
+    block_rows: usize,
+    row: usize,
+    x: f32,
+    row_usage: usize,
+) -> f32 {
+    let usage = row_usage as f32;
+    let row_crowding = match config.congestion {
+        CongestionCurve::Linear => config.beta * usage,
+        CongestionCurve::Quadratic(scale) => config.beta * (1.0 + usage / scale) * usage,
+    };
+
+    // Direction-sensitive horizontal movement
+    let delta_x = x - block.x;
+    let alpha = if delta_x > 0.0 {
+        params.alpha_right // Move right penalty
+    } else {
+        params.alpha_left // Move Left Reward
+    };
+    let dx = alpha * delta_x.abs();
+
+    // Y-displacement, considering how well the height fits the rows
+    let placed_y = params.origin_y + row as f32 * params.step_y;
+    let dy = (block.y - placed_y).abs()
+        + (block.h - (block_rows as f32 * params.step_y)).abs() * config.height_penalty;
+
+    let movement = match config.norm {
+        DisplacementNorm::L1 => dy + dx,
+        DisplacementNorm::L2 => (dy * dy + dx * dx).sqrt(),
+        DisplacementNorm::Squared => dy * dy + dx * dx,
+    };
+
+    // Displacement as the metrics measure it, against the limit
+    let over = match config.max_displacement {
+        Some(max) => ((x - block.x).abs() + (placed_y - block.y).abs() - max).max(0.0),
+        None => 0.0,
+    };
+    let limit_cost = match config.limit {
+        _ if over <= 0.0 => 0.0,
+        DisplacementLimit::Hard => HARD_LIMIT_COST + over,
+        DisplacementLimit::Soft(weight) => weight * over,
+    };
+    movement * block.weight + row_crowding + limit_cost
+}
+
+// Order the blocks are dropped in: higher priority first, then by the
+// key (normally the preferred x), and then by tag -- compared the same
+// way as legal_block_cmp_x
+fn drop_order<F>(blocks: &mut Vec<LegalBlock>, key: F)
+where
+    F: Fn(&LegalBlock) -> f32,
+{
+    blocks.sort_by(|a, b| {
+        if a.priority != b.priority {
+            return b.priority.cmp(&a.priority);
+        }
+        let (key_a, key_b) = (key(a), key(b));
+        if key_a < key_b {
+            return std::cmp::Ordering::Less;
+        }
+        if key_a > key_b {
+            return std::cmp::Ordering::Greater;
+        }
+        a.tag.cmp(&b.tag)
+    });
+}
 
 pub fn legalize(lp: &LegalProblem) -> Vec<LegalPosition> {
+    legalize_config(lp, &TetrisConfig::default())
+}
+
+pub fn legalize_config(lp: &LegalProblem, config: &TetrisConfig) -> Vec<LegalPosition> {
     //println!("Tetris placement legalizer"); // (optimized with directional cost)
 
     let mut blocks = lp.blocks.clone();
@@ -9,29 +299,16 @@ pub fn legalize(lp: &LegalProblem) -> Vec<LegalPosition> {
 
     // Sort blocks by their preferred X position
     // Sorting: prioritize blocks on the left
-    blocks.sort_by(|a, b| a.x.partial_cmp(&b.x).expect("Could not compare"));
+    drop_order(&mut blocks, |b| b.x);
 
     //Initialize left edge
     let mut legal_positions = Vec::new();
     let mut left_edges = vec![params.origin_x; params.grid_y];
     let mut row_usage = vec![0usize; params.grid_y]; // Track usage of each line
 
-    // Direction-sensitive cost factor
-    //const ALPHA_RIGHT: f32 = 2.0; // Penalty factor for moving to the right (higher)
-    //const ALPHA_LEFT: f32 = 0.5;  // The reward factor for moving left (lower)
-    const BETA: f32 = 0.5; // Row congestion penalty coefficient
-
     //Go through each block and find the best place to put it
     for block in &blocks {
-        // Modified: Use ceil() to calculate required rows and ensure minimum 1 row
-        let block_rows = (block.h / params.step_y).ceil() as usize;
-        let block_rows = block_rows.max(1); // Ensure at least 1 row
-
-        // Modified: Dynamic search range calculation with floor() for safety
-        let best_row = ((block.y - params.origin_y) / params.step_y).floor() as usize;
-        let search_radius = (5 * params.grid_y / 100).max(5); // At least 5 rows or 5% of total
-        let low_row = best_row.saturating_sub(search_radius);
-        let high_row = (best_row + search_radius).min(params.grid_y.saturating_sub(block_rows));
+        let (block_rows, best_row, low_row, high_row) = search_rows(block, params, config);
 
         let mut best_row = best_row;
         let mut best_cost = f32::MAX;
@@ -45,24 +322,7 @@ pub fn legalize(lp: &LegalProblem) -> Vec<LegalPosition> {
                 .map(|r| left_edges.get(r).unwrap_or(&params.origin_x))
                 .fold(params.origin_x, |a, &b| a.max(b));
 
-            // Original congestion calculation with dynamic beta
-            let dynamic_beta = BETA * (1.0 + row_usage[row] as f32 / 10.0);
-
-            // Original direction-sensitive cost calculation
-            let delta_x = left - block.x;
-            let alpha = if delta_x > 0.0 {
-                params.alpha_right // Move right penalty
-            } else {
-                params.alpha_left // Move Left Reward
-            };
-
-            // Modified: Improved Y-displacement calculation considering height
-            let placed_y = params.origin_y + row as f32 * params.step_y;
-            let delta_y = (block.y - placed_y).abs()
-                + (block.h - (block_rows as f32 * params.step_y)).abs() * 0.1;
-
-            let row_crowding = dynamic_beta * (row_usage[row] as f32);
-            let cost = delta_y + alpha * delta_x.abs() + row_crowding;
+            let cost = placement_cost(block, params, config, block_rows, row, left, row_usage[row]);
 
             if cost < best_cost {
                 best_row = row;
@@ -101,71 +361,428 @@ pub fn legalize(lp: &LegalProblem) -> Vec<LegalPosition> {
     legal_positions
 }
 
-pub fn legalize_floorplan(lp: &LegalProblem) -> Vec<LegalPosition> {
-    //println!("Floorplan legalizer with x-compaction (Tetris-style left-packing)");
+// Each row keeps a pair of frontiers.  Working inward, the free space
+// in a row is between the frontiers, and they close toward the
+// middle.  Working outward, both frontiers start at the center of the
+// row, and the free space is outside of them.
+struct Frontiers {
+    left: Vec<f32>,
+    right: Vec<f32>,
+    extent: Vec<f32>, // Rightmost edge of anything in the row
+    row_usage: Vec<usize>,
+    outward: bool,
+}
 
-    let mut blocks = lp.blocks.clone();
+impl Frontiers {
+    // Free space (low, high) for a block on one side, over a span of rows
+    fn space(&self, params: &LegalParams, rows: std::ops::Range<usize>, left_side: bool) -> (f32, f32) {
+        let core_left = params.origin_x;
+        let core_right = params.origin_x + params.grid_x as f32 * params.step_x;
+        let mut low = core_left;
+        let mut high = core_right;
+        for r in rows {
+            let (a, b) = if !self.outward {
+                (self.left[r], self.right[r])
+            } else if left_side {
+                (core_left, self.left[r])
+            } else {
+                (self.right[r], core_right)
+            };
+            low = low.max(a);
+            high = high.min(b);
+        }
+        (low, high)
+    }
+
+    fn claim(&mut self, rows: std::ops::Range<usize>, x: f32, w: f32, left_side: bool) {
+        for r in rows {
+            match (self.outward, left_side) {
+                (false, true) => self.left[r] = x + w,
+                (false, false) => self.right[r] = x,
+                (true, true) => self.left[r] = x,
+                (true, false) => self.right[r] = x + w,
+            }
+            self.extent[r] = self.extent[r].max(x + w);
+            self.row_usage[r] += 1;
+        }
+    }
+}
+
+// Place blocks, in the order given, against a pair of frontiers.  Each
+// block has a preferred side; outward, the other side is tried as well.
+// Blocks are clamped toward their preferred x within the free space,
+// so in a sparse row they need not move at all.
+fn legalize_frontiers(
+    lp: &LegalProblem,
+    config: &TetrisConfig,
+    order: Vec<(LegalBlock, bool)>,
+    mut frontiers: Frontiers,
+) -> Vec<LegalPosition> {
     let params = &lp.params;
+    let mut legal_positions = Vec::new();
 
-    //Identify all unique y positions (y1 & y2 for each block)
-    let mut y_points = Vec::new();
-    for block in &blocks {
-        y_points.push(block.y);
-        y_points.push(block.y + block.h);
+    for (block, left_side) in &order {
+        let (block_rows, best_row, low_row, high_row) = search_rows(block, params, config);
+        let sides = if frontiers.outward {
+            vec![*left_side, !*left_side]
+        } else {
+            vec![*left_side]
+        };
+
+        // Search near the preferred row first, and then the whole core
+        let mut best = None;
+        for (low, high) in [(low_row, high_row), (0, params.grid_y.saturating_sub(block_rows))] {
+            let mut best_cost = f32::MAX;
+            for row in low..=high {
+                for side in &sides {
+                    let (a, b) = frontiers.space(params, row..row + block_rows, *side);
+                    if a + block.w > b {
+                        continue;
+                    }
+                    let x = block.x.max(a).min(b - block.w);
+                    let usage = frontiers.row_usage[row];
+                    let cost = placement_cost(block, params, config, block_rows, row, x, usage);
+                    if cost < best_cost {
+                        best_cost = cost;
+                        best = Some((row, x, *side));
+                    }
+                }
+            }
+            if best.is_some() {
+                break;
+            }
+        }
+
+        let (row, x) = match best {
+            Some((row, x, side)) => {
+                frontiers.claim(row..row + block_rows, x, block.w, side);
+                (row, x)
+            }
+            None => {
+                // No room anywhere -- go past everything in the preferred
+                // rows, the way the left-packing Tetris overflows
+                #[cfg(feature = "ldbg")]
+                println!("**** No room for block {}, placed past the core", block.tag);
+                let rows = best_row..best_row + block_rows;
+                let core_right = params.origin_x + params.grid_x as f32 * params.step_x;
+                let x = rows.clone().map(|r| frontiers.extent[r]).fold(core_right, f32::max);
+                for r in rows {
+                    frontiers.extent[r] = x + block.w;
+                    frontiers.row_usage[r] += 1;
+                }
+                (best_row, x)
+            }
+        };
+
+        legal_positions.push(LegalPosition {
+            block_tag: block.tag,
+            x,
+            y: params.origin_y + row as f32 * params.step_y,
+            h: block.h,
+            w: block.w,
+            original_x: block.x,
+            original_y: block.y,
+        });
     }
 
-    //Sort and deduplicate the y positions
-    y_points.sort_by(|a, b| a.partial_cmp(b).unwrap());
-    y_points.dedup();
+    legal_positions
+}
+
+// Bidirectional Tetris: blocks are taken from both ends of the x
+// ordering, whichever side has consumed less width so far, and packed
+// from both sides of the core toward the middle.  Blocks with a higher
+// priority are all taken before any with a lower one.
+pub fn legalize_inward(lp: &LegalProblem, config: &TetrisConfig) -> Vec<LegalPosition> {
+    let params = &lp.params;
+    let mut blocks = lp.blocks.clone();
+    drop_order(&mut blocks, |b| b.x);
+
+    let mut order = Vec::new();
+    let mut left_taken = 0.0;
+    let mut right_taken = 0.0;
+    for group in blocks.chunk_by(|a, b| a.priority == b.priority) {
+        let mut lo = 0;
+        let mut hi = group.len();
+        while lo < hi {
+            if left_taken <= right_taken {
+                left_taken += group[lo].w;
+                order.push((group[lo], true));
+                lo += 1;
+            } else {
+                hi -= 1;
+                right_taken += group[hi].w;
+                order.push((group[hi], false));
+            }
+        }
+    }
 
-    //Map each vertical span (y1..y2) to a mutable left edge X position
-    let mut y_segments = vec![params.origin_x; y_points.len() - 1];
+    let core_right = params.origin_x + params.grid_x as f32 * params.step_x;
+    let frontiers = Frontiers {
+        left: vec![params.origin_x; params.grid_y],
+        right: vec![core_right; params.grid_y],
+        extent: vec![params.origin_x; params.grid_y],
+        row_usage: vec![0usize; params.grid_y],
+        outward: false,
+    };
+    legalize_frontiers(lp, config, order, frontiers)
+}
 
-    //find index of a y value in y_points
-    let find_y_index = |y: f32| -> usize {
-        y_points
-            .binary_search_by(|probe| probe.partial_cmp(&y).unwrap())
-            .unwrap_or_else(|i| i.saturating_sub(1))
+// Center-out Tetris: blocks closest to the middle of the core go first,
+// and each row fills from its center toward both edges.
+pub fn legalize_outward(lp: &LegalProblem, config: &TetrisConfig) -> Vec<LegalPosition> {
+    let params = &lp.params;
+    let center = params.origin_x + params.grid_x as f32 * params.step_x / 2.0;
+    let mut blocks = lp.blocks.clone();
+    let offset = |b: &LegalBlock| (b.x + b.w / 2.0 - center).abs();
+    drop_order(&mut blocks, offset);
+
+    let order = blocks
+        .iter()
+        .map(|b| (*b, b.x + b.w / 2.0 < center))
+        .collect();
+    let frontiers = Frontiers {
+        left: vec![center; params.grid_y],
+        right: vec![center; params.grid_y],
+        extent: vec![params.origin_x; params.grid_y],
+        row_usage: vec![0usize; params.grid_y],
+        outward: true,
     };
+    legalize_frontiers(lp, config, order, frontiers)
+}
+
+// Gap-aware Tetris.  Rather than a single frontier per row, the free
+// space in every row is tracked, and each block drops into the gap
+// nearest its preferred x that it fits in.  Fixed blocks (macros,
+// blockages, or cells that must not move) are carved out of the rows
+// before anything else is placed, and are not part of the result.
+pub fn legalize_gaps(
+    lp: &LegalProblem,
+    config: &TetrisConfig,
+    fixed: &Vec<LegalPosition>,
+) -> Vec<LegalPosition> {
+    let params = &lp.params;
+    let mut blocks = lp.blocks.clone();
+    drop_order(&mut blocks, |b| b.x);
 
-    //Sort blocks by x (left to right)
-    blocks.sort_by(|a, b| a.x.partial_cmp(&b.x).unwrap());
+    let mut space = FreeSpace::new(params);
+    for f in fixed {
+        space.carve_rect(f.x, f.y, f.w, f.h);
+    }
 
+    // Anything that does not fit at all goes past the right edge
+    let core_right = params.origin_x + params.grid_x as f32 * params.step_x;
+    let mut overflow = vec![core_right; params.grid_y];
+    let mut row_usage = vec![0usize; params.grid_y];
     let mut legal_positions = Vec::new();
 
     for block in &blocks {
-        let y_start = find_y_index(block.y);
-        let y_end = find_y_index(block.y + block.h);
+        let (block_rows, best_row, low_row, high_row) = search_rows(block, params, config);
+
+        // Search near the preferred row first, and then the whole core
+        let mut best = None;
+        if block_rows <= params.grid_y {
+            for (low, high) in [(low_row, high_row), (0, params.grid_y - block_rows)] {
+                let mut best_cost = f32::MAX;
+                for row in low..=high {
+                    if let Some(x) = space.nearest_fit(row..row + block_rows, block.x, block.w) {
+                        let cost =
+                            placement_cost(block, params, config, block_rows, row, x, row_usage[row]);
+                        if cost < best_cost {
+                            best_cost = cost;
+                            best = Some((row, x));
+                        }
+                    }
+                }
+                if best.is_some() {
+                    break;
+                }
+            }
+        }
 
-        //Determine the left-most X that this block can be placed at
-        let mut max_x = params.origin_x;
-        for y_idx in y_start..y_end {
-            if y_segments[y_idx] > max_x {
-                max_x = y_segments[y_idx];
+        let rows = best_row..(best_row + block_rows).min(params.grid_y);
+        let (row, x) = match best {
+            Some((row, x)) => {
+                space.carve(row..row + block_rows, x, block.w);
+                (row, x)
+            }
+            None => {
+                #[cfg(feature = "ldbg")]
+                println!("**** No gap for block {}, placed past the core", block.tag);
+                let x = rows.clone().map(|r| overflow[r]).fold(core_right, f32::max);
+                for r in rows.clone() {
+                    overflow[r] = x + block.w;
+                }
+                (best_row, x)
             }
+        };
+        for r in row..(row + block_rows).min(params.grid_y) {
+            row_usage[r] += 1;
         }
-        // println!("Pack block {} at {} {}", block.tag, max_x, block.y);
 
-        // lace the block at max x
         legal_positions.push(LegalPosition {
             block_tag: block.tag,
-            x: max_x,
-            y: block.y, //y unchange
+            x,
+            y: params.origin_y + row as f32 * params.step_y,
             h: block.h,
             w: block.w,
             original_x: block.x,
             original_y: block.y,
         });
+    }
+
+    legal_positions
+}
+
+// Gap-aware Tetris for mixed designs: the macros are legalized first
+// (and moved off any fixed blocks), and then fixed along with them
+// while the standard cells drop into the gaps.
+pub fn legalize_gaps_macros(
+    lp: &LegalProblem,
+    config: &TetrisConfig,
+    fixed: &Vec<LegalPosition>,
+) -> Vec<LegalPosition> {
+    let (macros, cells) = macros::split(&lp.blocks, &lp.params);
+    if macros.is_empty() {
+        return legalize_gaps(lp, config, fixed);
+    }
+    let macros = LegalProblem {
+        blocks: macros,
+        params: lp.params.clone(),
+    };
+    let mut legal_positions =
+        super::fixed::repair(&macros, macros::legalize_macros(&lp.params, &macros.blocks), fixed, config);
+    let cells = LegalProblem {
+        blocks: cells,
+        params: lp.params.clone(),
+    };
+    let mut obstacles = legal_positions.clone();
+    obstacles.extend(fixed.iter().copied());
+    legal_positions.extend(legalize_gaps(&cells, config, &obstacles));
+    legal_positions
+}
+
+// Multi-pass Tetris: legalize with each of the directions given, and
+// keep the result with the lowest total displacement.
+pub fn legalize_passes(
+    lp: &LegalProblem,
+    directions: &[TetrisDirection],
+    regions: usize,
+    config: &TetrisConfig,
+    fixed: &Vec<LegalPosition>,
+) -> Vec<LegalPosition> {
+    let mut best = Vec::new();
+    let mut best_displace = f32::MAX;
+    for direction in directions {
+        let legal_positions = legalize_direction(lp, *direction, regions, config, fixed);
+        let displace = Metrics::new(&legal_positions).total;
+        #[cfg(feature = "ldbg")]
+        println!("Tetris pass {:?} displacement {}", direction, displace);
+        if displace < best_displace {
+            best_displace = displace;
+            best = legal_positions;
+        }
+    }
+    best
+}
+
+// Edges closer together than this are the same edge
+const FLOORPLAN_TOLERANCE: f32 = 1.0e-3;
+
+// The distinct y edges of a floorplan, with edges within tolerance of
+// each other merged.  The segments between neighbouring edges are the
+// units the compactor tracks a frontier for.
+struct Breakpoints {
+    points: Vec<f32>,
+}
+
+impl Breakpoints {
+    fn new(blocks: &Vec<LegalBlock>) -> Breakpoints {
+        let mut edges = Vec::with_capacity(blocks.len() * 2);
+        for block in blocks {
+            edges.push(block.y);
+            edges.push(block.y + block.h);
+        }
+        edges.sort_by(|a, b| a.partial_cmp(b).expect("Could not compare"));
+
+        let mut points: Vec<f32> = Vec::new();
+        for y in edges {
+            match points.last() {
+                Some(&last) if y - last <= FLOORPLAN_TOLERANCE => {}
+                _ => points.push(y),
+            }
+        }
+        Breakpoints { points }
+    }
+
+    fn segments(&self) -> usize {
+        self.points.len().saturating_sub(1)
+    }
+
+    // Index of the breakpoint that y was merged into
+    fn index(&self, y: f32) -> usize {
+        // First point not more than the tolerance below y; every block
+        // edge has one, since the points came from the edges
+        let i = self.points.partition_point(|&p| p < y - FLOORPLAN_TOLERANCE);
+        i.min(self.points.len().saturating_sub(1))
+    }
+
+    // Segments covered by something from y to y + h.  Anything with a
+    // height below the tolerance still covers the segment it sits in.
+    fn span(&self, y: f32, h: f32) -> std::ops::Range<usize> {
+        let start = self.index(y);
+        let end = self.index(y + h).max(start + 1).min(self.segments());
+        start.min(end)..end
+    }
+}
+
+// Floorplan legalizer: blocks keep their y, and slide left (in order
+// of x) until they touch the core edge or a block already placed that
+// shares some of their vertical span.
+pub fn legalize_floorplan(lp: &LegalProblem) -> Vec<LegalPosition> {
+    let params = &lp.params;
+    let mut blocks = lp.blocks.clone();
+    if blocks.is_empty() {
+        return Vec::new();
+    }
+    blocks.sort_by(legal_block_cmp_x);
+
+    // Right-hand frontier of what has been placed, in each segment
+    let breakpoints = Breakpoints::new(&blocks);
+    let mut frontier = vec![params.origin_x; breakpoints.segments()];
+
+    let mut legal_positions = Vec::with_capacity(blocks.len());
+    for block in &blocks {
+        let span = breakpoints.span(block.y, block.h);
+        let x = frontier[span.clone()]
+            .iter()
+            .fold(params.origin_x, |a, &b| a.max(b));
+        // println!("Pack block {} at {} {}", block.tag, x, block.y);
 
-        //Update all y_segments that this block covers
-        for y_idx in y_start..y_end {
-            y_segments[y_idx] = max_x + block.w;
+        legal_positions.push(LegalPosition {
+            block_tag: block.tag,
+            x,
+            y: block.y, // y unchanged
+            h: block.h,
+            w: block.w,
+            original_x: block.x,
+            original_y: block.y,
+        });
+        for f in &mut frontier[span] {
+            *f = x + block.w;
         }
     }
 
     legal_positions
 }
 
+// Floorplan legalization in two dimensions: rather than sliding every
+// block left, each overlap is removed by moving the blocks apart
+// horizontally or vertically, whichever displaces them less, staying
+// inside the core.  Blocks are not snapped to rows or sites.
+pub fn legalize_floorplan_2d(lp: &LegalProblem) -> Vec<LegalPosition> {
+    macros::separate(&lp.params, &lp.blocks, false)
+}
+
 pub fn legalize_standard(lp: &LegalProblem) -> Vec<LegalPosition> {
     let mut blocks = lp.blocks.clone();
     let params = &lp.params;
@@ -175,7 +792,7 @@ pub fn legalize_standard(lp: &LegalProblem) -> Vec<LegalPosition> {
     let target_row_width = total_width / params.grid_y.max(1) as f32;
 
     //sort by Y
-    blocks.sort_by(|a, b| a.y.partial_cmp(&b.y).unwrap());
+    blocks.sort_by(legal_block_cmp_y);
 
     let mut unplaced = blocks;
     let mut legal_positions = Vec::new();
@@ -203,26 +820,112 @@ pub fn legalize_standard(lp: &LegalProblem) -> Vec<LegalPosition> {
         }
 
         //sort by X & place
-        row_blocks.sort_by(|a, b| a.x.partial_cmp(&b.x).unwrap());
+        row_blocks.sort_by(legal_block_cmp_x);
 
-        let mut current_x = params.origin_x;
         let current_y = params.origin_y + current_row as f32 * params.step_y;
-
-        for block in row_blocks {
-            legal_positions.push(LegalPosition {
-                block_tag: block.tag,
-                x: current_x,
-                y: current_y,
-                h: block.h,
-                w: block.w,
-                original_x: block.x,
-                original_y: block.y,
-            });
-            current_x += block.w;
-        }
+        let right = params.origin_x + params.grid_x as f32 * params.step_x;
+        placerow::emit_row(&row_blocks, params.origin_x, right, current_y, &mut legal_positions);
 
         current_row += 1;
     }
 
     legal_positions
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::legalize::{LegalBlock, LegalParams, LegalProblem};
+
+    fn problem(blocks: &[(f32, f32, f32, f32)]) -> LegalProblem {
+        LegalProblem {
+            blocks: blocks
+                .iter()
+                .enumerate()
+                .map(|(tag, &(x, y, w, h))| LegalBlock {
+                    tag,
+                    x,
+                    y,
+                    h,
+                    w,
+                    weight: 1.0,
+                    priority: 0,
+                })
+                .collect(),
+            params: LegalParams {
+                grid_x: 100,
+                grid_y: 10,
+                origin_x: 0.0,
+                origin_y: 0.0,
+                step_x: 1.0,
+                step_y: 10.0,
+                alpha_right: 2.0,
+                alpha_left: 0.5,
+            },
+        }
+    }
+
+    fn x_of(positions: &Vec<LegalPosition>, tag: usize) -> f32 {
+        positions.iter().find(|p| p.block_tag == tag).unwrap().x
+    }
+
+    #[test]
+    fn floorplan_empty() {
+        assert!(legalize_floorplan(&problem(&[])).is_empty());
+    }
+
+    #[test]
+    fn floorplan_touching_edges_do_not_interact() {
+        // One block directly on top of the other
+        let lp = problem(&[(5.0, 0.0, 10.0, 10.0), (8.0, 10.0, 10.0, 10.0)]);
+        let result = legalize_floorplan(&lp);
+        assert_eq!(x_of(&result, 0), 0.0);
+        assert_eq!(x_of(&result, 1), 0.0);
+    }
+
+    #[test]
+    fn floorplan_overlapping_blocks_are_pushed_right() {
+        let lp = problem(&[(0.0, 0.0, 10.0, 10.0), (5.0, 5.0, 10.0, 10.0)]);
+        let result = legalize_floorplan(&lp);
+        assert_eq!(x_of(&result, 0), 0.0);
+        assert_eq!(x_of(&result, 1), 10.0);
+    }
+
+    #[test]
+    fn floorplan_nested_spans() {
+        // A tall block, then two short ones within its span, and one
+        // that covers both of the short ones
+        let lp = problem(&[
+            (0.0, 0.0, 10.0, 30.0),
+            (12.0, 5.0, 4.0, 5.0),
+            (14.0, 15.0, 6.0, 5.0),
+            (20.0, 4.0, 3.0, 20.0),
+        ]);
+        let result = legalize_floorplan(&lp);
+        assert_eq!(x_of(&result, 0), 0.0);
+        assert_eq!(x_of(&result, 1), 10.0);
+        assert_eq!(x_of(&result, 2), 10.0);
+        assert_eq!(x_of(&result, 3), 16.0);
+    }
+
+    #[test]
+    fn floorplan_near_coincident_edges() {
+        // Edges that differ only by rounding noise are the same edge
+        let lp = problem(&[
+            (0.0, 0.0, 10.0, 10.0),
+            (3.0, 10.0 - 1.0e-5, 10.0, 10.0),
+            (6.0, 10.0 + 1.0e-5, 10.0, 10.0),
+        ]);
+        let result = legalize_floorplan(&lp);
+        assert_eq!(x_of(&result, 0), 0.0);
+        assert_eq!(x_of(&result, 1), 0.0);
+        assert_eq!(x_of(&result, 2), 10.0);
+    }
+
+    #[test]
+    fn floorplan_zero_height_block() {
+        let lp = problem(&[(0.0, 0.0, 10.0, 10.0), (2.0, 5.0, 4.0, 0.0)]);
+        let result = legalize_floorplan(&lp);
+        assert_eq!(x_of(&result, 1), 10.0);
+    }
+}
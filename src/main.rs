@@ -1,4 +1,16 @@
 use argh::FromArgs;
+use legalize::legalize::bound::{self, BoundConfig};
+use legalize::legalize::detailed::{self, DetailedConfig, DetailedObjective};
+use legalize::legalize::generate::{self, GenerateConfig, WidthDistribution};
+use legalize::legalize::hcwt_legal::{HcwtConfig, HcwtMode, HcwtStats};
+use legalize::legalize::limit;
+use legalize::legalize::random::{self, RandomConfig};
+use legalize::legalize::spread::{self, SpreadConfig};
+use legalize::legalize::{LegalKind, LegalPosition, LegalProblem};
+use legalize::legalize::tetris::{
+    CongestionCurve, DisplacementLimit, DisplacementNorm, TetrisConfig, TetrisDirection,
+};
+use std::sync::Mutex;
 #[derive(FromArgs)]
 /// Placement legalization
 struct Args {
@@ -6,14 +18,166 @@ struct Args {
     #[argh(switch, short = 't')]
     tetris: bool,
 
+    /// tetris direction: left, right, both, inward, outward or gaps
+    #[argh(option)]
+    direction: Option<TetrisDirection>,
+
+    /// tetris direction to try; repeat to keep the best of several passes
+    #[argh(option)]
+    pass: Vec<TetrisDirection>,
+
+    /// number of row bands to compare in when the direction is both
+    #[argh(option)]
+    regions: Option<usize>,
+
+    /// tetris rows searched, as a percent of all rows (default 5)
+    #[argh(option)]
+    search_percent: Option<usize>,
+
+    /// tetris minimum rows searched (default 5)
+    #[argh(option)]
+    search_min: Option<usize>,
+
+    /// tetris row congestion penalty (default 0.5)
+    #[argh(option)]
+    beta: Option<f32>,
+
+    /// tetris congestion curve: linear, quadratic or quadratic:<scale>
+    #[argh(option)]
+    congestion: Option<CongestionCurve>,
+
+    /// tetris displacement norm: l1, l2 or squared
+    #[argh(option)]
+    norm: Option<DisplacementNorm>,
+
+    /// tetris penalty for block height not filling its rows (default 0.1)
+    #[argh(option)]
+    height_penalty: Option<f32>,
+
+    /// furthest any block should move, with any legalizer
+    #[argh(option)]
+    max_displacement: Option<f32>,
+
+    /// displacement limit in the tetris cost: hard, soft or soft:<weight>
+    #[argh(option)]
+    limit: Option<DisplacementLimit>,
+
+    /// rounds of re-legalizing blocks past the limit (default 4)
+    #[argh(option)]
+    limit_rounds: Option<usize>,
+
+    /// tetris: legalize the macros first, then fit the cells around them
+    #[argh(switch)]
+    macros_first: bool,
+
+    /// split the core into this many bands, legalized in parallel
+    #[argh(option)]
+    bands: Option<usize>,
+
+    /// split each band into this many columns
+    #[argh(option)]
+    columns: Option<usize>,
+
     /// hcwt legalization
     #[argh(switch, short = 'h')]
     hcwt: bool,
 
+    /// hcwt mode: mixed, standard or best
+    #[argh(option)]
+    hcwt_mode: Option<HcwtMode>,
+
+    /// hcwt cost of the upper row relative to the lower (default 0.8)
+    #[argh(option)]
+    upper_weight: Option<f32>,
+
+    /// hcwt weight of horizontal movement in the upper row (default 0.1)
+    #[argh(option)]
+    upper_horizontal_weight: Option<f32>,
+
+    /// hcwt upper horizontal weight for the last row pairs (default 0.6)
+    #[argh(option)]
+    top_horizontal_weight: Option<f32>,
+
+    /// hcwt slack over the target for packing without solving (default 10)
+    #[argh(option)]
+    hard_max_slack: Option<f32>,
+
+    /// hcwt slack over the target in the last row (default 1000)
+    #[argh(option)]
+    last_row_slack: Option<f32>,
+
+    /// hcwt row length difference, in widest blocks (default 4)
+    #[argh(option)]
+    delta_factor: Option<f32>,
+
+    /// hcwt blocks taken per row, in row capacities (default 2)
+    #[argh(option)]
+    fill_factor: Option<f32>,
+
+    /// hcwt nodes kept at each level, lowest cost first
+    #[argh(option)]
+    beam_width: Option<usize>,
+
+    /// hcwt row length bucket size for filtering (default exact)
+    #[argh(option)]
+    beam_bucket: Option<f32>,
+
+    /// hcwt rows each block can be placed in (default 2, at most 4)
+    #[argh(option)]
+    lookahead: Option<usize>,
+
+    /// report hcwt solve statistics
+    #[argh(switch)]
+    stats: bool,
+
     /// rowfill legalization
     #[argh(switch, short = 'r')]
     rowfill: bool,
 
+    /// place the rowfill rows exactly on sites, by dynamic programming
+    #[argh(switch)]
+    exact_rows: bool,
+
+    /// spread dense areas by min-cost flow before legalizing
+    #[argh(switch)]
+    spread: bool,
+
+    /// detailed placement after legalizing, to reduce displacement
+    #[argh(switch)]
+    detailed: bool,
+
+    /// detailed placement objective: displacement or hpwl
+    #[argh(option)]
+    objective: Option<DetailedObjective>,
+
+    /// rounds of detailed placement moves (default 3)
+    #[argh(option)]
+    detailed_passes: Option<usize>,
+
+    /// cells reordered together in detailed placement (default 3, at most 4)
+    #[argh(option)]
+    window: Option<usize>,
+
+    /// cells either side of the target tried in detailed swaps (default 3)
+    #[argh(option)]
+    swap_range: Option<usize>,
+
+    /// nets file for the hpwl objective
+    #[argh(option)]
+    nets: Option<String>,
+
+    /// exact row legalization by dynamic programming
+    #[argh(switch)]
+    dp: bool,
+
+    /// seed for randomized legalization, keeping the best of several tries
+    #[argh(option)]
+    seed: Option<u64>,
+
+    /// randomized tries, including the unjittered one (default 8)
+    #[argh(option)]
+    tries: Option<usize>,
+
     /// row number adjustment
     #[argh(option, short = 'd')]
     delta_row: Option<i32>,
@@ -29,15 +193,160 @@ struct Args {
     /// postScript output file
     #[argh(option, short = 'P')]
     postscript: Option<String>,
+
+    /// report utilization before legalizing, in this many row bands
+    #[argh(option, short = 'u')]
+    utilization: Option<usize>,
+
+    /// legalize even if the blocks cannot fit in the core
+    #[argh(switch)]
+    force: bool,
+
+    /// report displacement metrics
+    #[argh(switch, short = 'm')]
+    metrics: bool,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Generate(GenerateArgs),
+}
+
+#[derive(FromArgs)]
+/// Generate a synthetic legalization problem
+#[argh(subcommand, name = "generate")]
+struct GenerateArgs {
+    /// file to write
+    #[argh(option, short = 'o')]
+    output: String,
+
+    /// random seed (default 1)
+    #[argh(option)]
+    seed: Option<u64>,
+
+    /// number of rows (default 50)
+    #[argh(option)]
+    rows: Option<usize>,
+
+    /// sites in each row (default 800)
+    #[argh(option)]
+    sites: Option<usize>,
+
+    /// block area over the free core area (default 0.7)
+    #[argh(option)]
+    utilization: Option<f32>,
+
+    /// cell widths in sites: uniform:<min>:<max> or lognormal:<median>:<sigma>
+    #[argh(option)]
+    widths: Option<WidthDistribution>,
+
+    /// fraction of cells two or three rows tall (default 0)
+    #[argh(option)]
+    multi_row: Option<f32>,
+
+    /// number of macros (default 0)
+    #[argh(option)]
+    macros: Option<usize>,
+
+    /// number of clustered hotspots (default 0)
+    #[argh(option)]
+    hotspots: Option<usize>,
+
+    /// fraction of the cells in the hotspots (default 0.3)
+    #[argh(option)]
+    hotspot_fraction: Option<f32>,
+
+    /// number of fixed blockages (default 0)
+    #[argh(option)]
+    blockages: Option<usize>,
+}
+
+// Write a synthetic problem, instead of legalizing one
+fn generate(arguments: &GenerateArgs) {
+    let mut config = GenerateConfig::default();
+    if let Some(seed) = arguments.seed {
+        config.seed = seed;
+    }
+    if let Some(rows) = arguments.rows {
+        config.rows = rows;
+    }
+    if let Some(sites) = arguments.sites {
+        config.sites = sites;
+    }
+    if let Some(utilization) = arguments.utilization {
+        config.utilization = utilization;
+    }
+    if let Some(widths) = arguments.widths {
+        config.widths = widths;
+    }
+    if let Some(multi_row) = arguments.multi_row {
+        config.multi_row = multi_row;
+    }
+    if let Some(macros) = arguments.macros {
+        config.macros = macros;
+    }
+    if let Some(hotspots) = arguments.hotspots {
+        config.hotspots = hotspots;
+    }
+    if let Some(hotspot_fraction) = arguments.hotspot_fraction {
+        config.hotspot_fraction = hotspot_fraction;
+    }
+    if let Some(blockages) = arguments.blockages {
+        config.blockages = blockages;
+    }
+
+    let generated = generate::generate(&config);
+    println!(
+        "Generated {} blocks ({} blockages) in {} rows of {} sites, seed {}",
+        generated.problem.blocks.len(),
+        generated.fixed.len(),
+        config.rows,
+        config.sites,
+        config.seed
+    );
+    generated.problem.save_fixed(&arguments.output, &generated.fixed);
+}
+
+// Legalize around the fixed blocks, re-legalizing any blocks past the
+// displacement limit in the config, or with a seed, keep the best of
+// several randomized runs.  The legalizer is given the positions to
+// treat as fixed; if it cannot use them, whatever it puts in their
+// way is moved afterwards.
+fn run<F>(
+    lp: &LegalProblem,
+    fixed: &Vec<LegalPosition>,
+    config: &TetrisConfig,
+    rounds: usize,
+    random: &Option<RandomConfig>,
+    legalizer: &F,
+) -> Vec<LegalPosition>
+where
+    F: Fn(&LegalProblem, &Vec<LegalPosition>) -> Vec<LegalPosition>,
+{
+    let legalize = |p: &LegalProblem| limit::legalize(p, fixed, config, rounds, legalizer);
+    match random {
+        Some(random) => random::legalize(lp, random, &legalize),
+        None => legalize(lp),
+    }
 }
 
 fn main() {
     println!("Stand-alone placement legalizer");
     let arguments: Args = argh::from_env();
 
+    if let Some(Command::Generate(generate_args)) = &arguments.command {
+        generate(generate_args);
+        return;
+    }
+
     let mut lp;
+    let fixed;
     if arguments.file.is_some() {
-        lp = legalize::legalize::load(&arguments.file.unwrap());
+        (lp, fixed) = legalize::legalize::load_fixed(&arguments.file.unwrap());
     } else {
         println!("Must specify an input file");
         return;
@@ -49,18 +358,228 @@ fn main() {
         lp.rescale();
     }
 
+    let feasibility =
+        legalize::legalize::feasibility::analyze(&lp, &fixed, arguments.utilization.unwrap_or(1));
+    if arguments.utilization.is_some() {
+        println!("{}", feasibility);
+    }
+    for warning in feasibility.warnings() {
+        println!("Warning: {}", warning);
+    }
+    if let Err(e) = feasibility.check() {
+        println!("Infeasible: {}", e);
+        if !arguments.force {
+            return;
+        }
+    }
+
+    if arguments.spread && arguments.max_displacement.is_some() {
+        // The limit would be measured from the spread positions
+        println!("**** --max-displacement cannot be used with --spread");
+        return;
+    }
+
+    // Legalize the spread problem, measuring from the original
+    let mut original = None;
+    if arguments.spread {
+        let spread = spread::spread(&lp, &SpreadConfig::default());
+        original = Some(std::mem::replace(&mut lp, spread));
+    }
+
+    let bands = arguments.bands.unwrap_or(1);
+    let columns = arguments.columns.unwrap_or(1);
+    let random = arguments.seed.map(|seed| {
+        let mut config = RandomConfig {
+            seed,
+            ..Default::default()
+        };
+        if let Some(tries) = arguments.tries {
+            config.tries = tries;
+        }
+        config
+    });
+
+    // The Tetris cost model, also used to place blocks past the limit
+    // and blocks in the way of fixed ones, whatever the legalizer
+    let mut tetris_config = TetrisConfig::default();
+    if let Some(search_percent) = arguments.search_percent {
+        tetris_config.search_percent = search_percent;
+    }
+    if let Some(search_min) = arguments.search_min {
+        tetris_config.search_min = search_min;
+    }
+    if let Some(beta) = arguments.beta {
+        tetris_config.beta = beta;
+    }
+    if let Some(congestion) = arguments.congestion {
+        tetris_config.congestion = congestion;
+    }
+    if let Some(norm) = arguments.norm {
+        tetris_config.norm = norm;
+    }
+    if let Some(height_penalty) = arguments.height_penalty {
+        tetris_config.height_penalty = height_penalty;
+    }
+    tetris_config.max_displacement = arguments.max_displacement;
+    if let Some(limit) = arguments.limit {
+        tetris_config.limit = limit;
+    }
+    let rounds = arguments.limit_rounds.unwrap_or(4);
+    if !fixed.is_empty() {
+        println!("{} fixed blocks", fixed.len());
+    }
+    if arguments.max_displacement.is_none() && (arguments.limit.is_some() || arguments.limit_rounds.is_some()) {
+        println!("**** --limit and --limit-rounds need --max-displacement");
+        return;
+    }
+    if arguments.macros_first && (arguments.direction.is_some() || !arguments.pass.is_empty()) {
+        println!("**** --macros-first always uses gap-aware Tetris; it cannot take --direction or --pass");
+        return;
+    }
+
     let mut legal = Vec::new();
     if arguments.tetris {
-        legal = legalize::legalize::tetris::legalize(&lp);
+        let mut passes = arguments.pass.clone();
+        if passes.is_empty() {
+            passes.push(arguments.direction.unwrap_or(TetrisDirection::Left));
+        }
+        legal = run(&lp, &fixed, &tetris_config, rounds, &random, &|p: &LegalProblem, held: &Vec<LegalPosition>| {
+            let tetris = |tile: &LegalProblem| {
+                if arguments.macros_first {
+                    legalize::legalize::tetris::legalize_gaps_macros(tile, &tetris_config, held)
+                } else {
+                    legalize::legalize::tetris::legalize_passes(
+                        tile,
+                        &passes,
+                        arguments.regions.unwrap_or(1),
+                        &tetris_config,
+                        held,
+                    )
+                }
+            };
+            legalize::legalize::partition::legalize(p, held, &tetris_config, bands, columns, &tetris)
+        });
     }
     if arguments.hcwt {
-        legal = legalize::legalize::hcwt_legal::legalize(&lp);
+        let mut config = HcwtConfig::default();
+        if let Some(upper_weight) = arguments.upper_weight {
+            config.upper_weight = upper_weight;
+        }
+        if let Some(upper_horizontal_weight) = arguments.upper_horizontal_weight {
+            config.upper_horizontal_weight = upper_horizontal_weight;
+        }
+        if let Some(top_horizontal_weight) = arguments.top_horizontal_weight {
+            config.top_horizontal_weight = top_horizontal_weight;
+        }
+        if let Some(hard_max_slack) = arguments.hard_max_slack {
+            config.hard_max_slack = hard_max_slack;
+        }
+        if let Some(last_row_slack) = arguments.last_row_slack {
+            config.last_row_slack = last_row_slack;
+        }
+        if let Some(delta_factor) = arguments.delta_factor {
+            config.delta_factor = delta_factor;
+        }
+        if let Some(fill_factor) = arguments.fill_factor {
+            config.fill_factor = fill_factor;
+        }
+        if let Some(mode) = arguments.hcwt_mode {
+            config.mode = mode;
+        }
+        config.beam_width = arguments.beam_width;
+        if let Some(lookahead) = arguments.lookahead {
+            config.lookahead = lookahead;
+        }
+        if let Some(beam_bucket) = arguments.beam_bucket {
+            config.beam_bucket = beam_bucket;
+        }
+        // Statistics add up over every tile and every try
+        let stats = Mutex::new(HcwtStats::default());
+        let hcwt = |tile: &LegalProblem| {
+            let (tile_legal, tile_stats) = legalize::legalize::hcwt_legal::legalize_stats(tile, &config);
+            stats.lock().unwrap().add(&tile_stats);
+            tile_legal
+        };
+        legal = run(&lp, &fixed, &tetris_config, rounds, &random, &|p: &LegalProblem, held: &Vec<LegalPosition>| {
+            legalize::legalize::partition::legalize(p, held, &tetris_config, bands, columns, &hcwt)
+        });
+        if arguments.stats {
+            println!("HCwT: {}", stats.lock().unwrap());
+        }
+    }
+    if arguments.rowfill && arguments.exact_rows {
+        legal = run(&lp, &fixed, &tetris_config, rounds, &random, &|p: &LegalProblem, held: &Vec<LegalPosition>| {
+            legalize::legalize::partition::legalize(p, held, &tetris_config, bands, columns, &legalize::legalize::rowfill::legalize_exact)
+        });
+    } else if arguments.rowfill {
+        legal = run(&lp, &fixed, &tetris_config, rounds, &random, &|p: &LegalProblem, held: &Vec<LegalPosition>| {
+            legalize::legalize::partition::legalize_kind(p, held, &tetris_config, LegalKind::RowFill, bands, columns)
+        });
     }
-    if arguments.rowfill {
-        legal = legalize::legalize::rowfill::legalize(&lp);
+
+    if arguments.dp {
+        legal = run(&lp, &fixed, &tetris_config, rounds, &random, &|p: &LegalProblem, held: &Vec<LegalPosition>| {
+            let dp = |tile: &LegalProblem| legalize::legalize::dp::legalize_fixed(tile, held);
+            legalize::legalize::partition::legalize(p, held, &tetris_config, bands, columns, &dp)
+        });
+    }
+
+    if let Some(original) = &original {
+        spread::restore(original, &mut legal);
+    }
+
+    if arguments.detailed {
+        let mut config = DetailedConfig::default();
+        if let Some(objective) = arguments.objective {
+            config.objective = objective;
+        }
+        if let Some(passes) = arguments.detailed_passes {
+            config.passes = passes;
+        }
+        if let Some(window) = arguments.window {
+            config.window = window;
+        }
+        if let Some(swap_range) = arguments.swap_range {
+            config.swap_range = swap_range;
+        }
+        let nets = arguments.nets.as_ref().map(detailed::load_nets).unwrap_or_default();
+        if config.objective == DetailedObjective::Hpwl && nets.is_empty() {
+            println!("**** The hpwl objective needs nets (--nets)");
+        }
+        let before = detailed::hpwl(&legal, &nets);
+        legal = detailed::improve(&lp, &legal, &fixed, &nets, &config);
+        if !nets.is_empty() {
+            println!("HPWL: {:.1} -> {:.1}", before, detailed::hpwl(&legal, &nets));
+        }
+    }
+
+    if let Some(max) = arguments.max_displacement {
+        let over = limit::over_limit(&legal, max);
+        println!("{} blocks moved more than {:.1}", over.len(), max);
+        for (tag, displacement) in over.iter().take(10) {
+            println!("  Block {} moved {:.1}", tag, displacement);
+        }
+    }
+
+    if arguments.metrics {
+        let metrics = legalize::legalize::Metrics::new(&legal);
+        println!(
+            "Displace: {:.1}  Max displace: {:.1}  Avg displace: {:.1}",
+            metrics.total, metrics.max, metrics.average
+        );
+        // Measure against the problem as given, not the spread one
+        let bound = bound::lower_bound(original.as_ref().unwrap_or(&lp), &BoundConfig::default());
+        match bound.gap(&metrics) {
+            Some(gap) if !legal.is_empty() => {
+                println!("Lower bound: {:.1}  Gap: {:.1}%", bound.total, gap)
+            }
+            _ => println!("Lower bound: {:.1}", bound.total),
+        }
     }
 
     if arguments.postscript.is_some() {
-        lp.postscript(&arguments.postscript.unwrap(), &legal);
+        let mut drawn = legal.clone();
+        drawn.extend(fixed.iter().copied());
+        lp.postscript(&arguments.postscript.unwrap(), &drawn);
     }
 }